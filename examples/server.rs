@@ -7,19 +7,21 @@
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use clap::{Parser, ValueEnum};
-use env_logger;
 use image::io::Reader as ImageReader;
 use image::GenericImageView;
-use log::info;
-use rfb::encodings::RawEncoding;
+use rfb::clients::ClientContext;
+use rfb::framebuffer::Framebuffer;
+use rfb::region::Rect;
 use rfb::rfb::{
-    FramebufferUpdate, KeyEvent, PixelFormat, ProtoVersion, Rectangle, SecurityType, SecurityTypes,
+    FramebufferUpdate, KeyEvent, PixelFormat, ProtoVersion, SecurityType, SecurityTypes,
 };
 use rfb::{
     pixel_formats::rgb_888,
-    server::{Server, VncServer, VncServerConfig, VncServerData},
+    server::{BackendError, Server, VncServer, VncServerConfig, VncServerData},
 };
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tracing::info;
 
 const WIDTH: usize = 1024;
 const HEIGHT: usize = 768;
@@ -71,11 +73,8 @@ enum Image {
     Black,
 }
 
-#[derive(Clone)]
 struct ExampleServer {
-    display: Image,
-    rgb_order: (u8, u8, u8),
-    big_endian: bool,
+    framebuffer: Arc<Mutex<Framebuffer>>,
 }
 
 #[tokio::main]
@@ -102,22 +101,30 @@ async fn main() -> Result<()> {
     );
 
     let config = VncServerConfig {
-        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9000),
+        listen: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9000).into()],
         version: ProtoVersion::Rfb38,
         sec_types: SecurityTypes(vec![SecurityType::None, SecurityType::VncAuthentication]),
         name: "rfb-example-server".to_string(),
+        ..Default::default()
     };
     let data = VncServerData {
         width: WIDTH as u16,
         height: HEIGHT as u16,
         input_pixel_format: pf.clone(),
     };
+    let rgb_order = (args.red_order, args.green_order, args.blue_order);
+    let mut framebuffer = Framebuffer::new(WIDTH as u16, HEIGHT as u16, pf);
+    draw(&mut framebuffer, args.image, args.big_endian, rgb_order);
+    let framebuffer = Arc::new(Mutex::new(framebuffer));
+
     let server = ExampleServer {
-        display: args.image,
-        rgb_order: (args.red_order, args.green_order, args.blue_order),
-        big_endian: args.big_endian,
+        framebuffer: framebuffer.clone(),
     };
     let s = VncServer::new(server, config, data);
+    framebuffer
+        .lock()
+        .unwrap()
+        .set_damage_notifier(s.damage_notifier());
     s.start().await?;
 
     Ok(())
@@ -150,26 +157,10 @@ fn order_to_index(order: u8, big_endian: bool) -> u8 {
     }
 }
 
-fn generate_color(index: u8, big_endian: bool) -> Vec<u8> {
-    const LEN: usize = WIDTH * HEIGHT * rgb_888::BYTES_PER_PIXEL;
-    let mut pixels = vec![0x0u8; LEN];
-
-    let idx = order_to_index(index, big_endian);
-
-    let mut x = 0;
-    for i in 0..pixels.len() {
-        if x == idx {
-            pixels[i] = 0xff;
-        }
-
-        if x == 3 {
-            x = 0;
-        } else {
-            x += 1;
-        }
-    }
-
-    pixels
+fn color_pixel(index: u8, big_endian: bool) -> [u8; rgb_888::BYTES_PER_PIXEL] {
+    let mut pixel = [0x0u8; rgb_888::BYTES_PER_PIXEL];
+    pixel[order_to_index(index, big_endian) as usize] = 0xff;
+    pixel
 }
 
 fn generate_image(name: &str, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec<u8> {
@@ -201,37 +192,40 @@ fn generate_image(name: &str, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec<
     pixels
 }
 
-fn generate_pixels(img: Image, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec<u8> {
-    const LEN: usize = WIDTH * HEIGHT * rgb_888::BYTES_PER_PIXEL;
-
+fn draw(fb: &mut Framebuffer, img: Image, big_endian: bool, rgb_order: (u8, u8, u8)) {
     let (r, g, b) = rgb_order;
+    let all = fb.bounds();
+    let stride = WIDTH * rgb_888::BYTES_PER_PIXEL;
 
-    match img {
-        Image::Oxide => generate_image("example-images/oxide.jpg", big_endian, rgb_order),
-        Image::TestTubes => generate_image("example-images/test-tubes.jpg", big_endian, rgb_order),
-        Image::Red => generate_color(r, big_endian),
-        Image::Green => generate_color(g, big_endian),
-        Image::Blue => generate_color(b, big_endian),
-        Image::White => vec![0xffu8; LEN],
-        Image::Black => vec![0x0u8; LEN],
-    }
+    let res = match img {
+        Image::Oxide => {
+            let pixels = generate_image("example-images/oxide.jpg", big_endian, rgb_order);
+            fb.blit(0, 0, WIDTH as u16, &pixels, stride)
+        }
+        Image::TestTubes => {
+            let pixels = generate_image("example-images/test-tubes.jpg", big_endian, rgb_order);
+            fb.blit(0, 0, WIDTH as u16, &pixels, stride)
+        }
+        Image::Red => fb.fill_rect(all, &color_pixel(r, big_endian)),
+        Image::Green => fb.fill_rect(all, &color_pixel(g, big_endian)),
+        Image::Blue => fb.fill_rect(all, &color_pixel(b, big_endian)),
+        Image::White => fb.fill_rect(all, &[0xff; rgb_888::BYTES_PER_PIXEL]),
+        Image::Black => fb.fill_rect(all, &[0x0; rgb_888::BYTES_PER_PIXEL]),
+    };
+    res.expect("pixels are in the framebuffer's format and size");
 }
 
 #[async_trait]
 impl Server for ExampleServer {
-    async fn get_framebuffer_update(&self) -> FramebufferUpdate {
-        let pixels_width = 1024;
-        let pixels_height = 768;
-        let pixels = generate_pixels(self.display, self.big_endian, self.rgb_order);
-        let r = Rectangle::new(
-            0,
-            0,
-            pixels_width,
-            pixels_height,
-            Box::new(RawEncoding::new(pixels)),
-        );
-        FramebufferUpdate::new(vec![r])
+    async fn get_framebuffer_update(
+        &self,
+        _ctx: &ClientContext,
+        area: Rect,
+    ) -> Result<FramebufferUpdate, BackendError> {
+        Ok(self.framebuffer.lock().unwrap().update(area))
     }
 
-    async fn key_event(&self, _ke: KeyEvent) {}
+    async fn key_event(&self, _ctx: &ClientContext, _ke: KeyEvent) -> Result<(), BackendError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,474 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Client connections
+//!
+//! A [`VncServer`](crate::server::VncServer) keeps a registry of the clients connected to it. Each
+//! client can be inspected through a [`ClientInfo`] snapshot and controlled through a
+//! [`ClientHandle`], which can disconnect the client or push messages to it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, watch};
+use tracing::info;
+
+use crate::encodings::EncodingType;
+use crate::link::LinkStats;
+use crate::listener::{ListenAddr, PeerAddr};
+use crate::metrics::{ConnectionMetrics, Counters, ServerMetrics, Totals};
+use crate::rfb::{PixelFormat, ProtoVersion, SecurityType};
+use crate::server::{DisconnectReason, OverflowPolicy, ServerError};
+
+/// Identifies a client connection for the lifetime of the server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientId(pub(crate) u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What a client is allowed to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ClientRole {
+    /// The client sees the display and its input is passed on to the backend.
+    #[default]
+    Interactive,
+
+    /// The client sees the display, but its keyboard, pointer and clipboard input is dropped.
+    ViewOnly,
+}
+
+/// The outcome of authorizing a client, returned by
+/// [`Server::client_authenticated`](crate::server::Server::client_authenticated).
+#[derive(Debug, Clone, Default)]
+pub struct Authorization {
+    /// Who the client is, if the backend knows.
+    pub identity: Option<String>,
+    pub role: ClientRole,
+}
+
+/// Identifies the client on whose behalf a [`Server`](crate::server::Server) callback is made.
+#[derive(Debug, Clone)]
+pub struct ClientContext {
+    pub id: ClientId,
+    pub addr: PeerAddr,
+
+    /// The listener the client connected through, or `None` if it was handed to the server with
+    /// [`VncServer::serve`](crate::server::VncServer::serve).
+    pub listener: Option<ListenAddr>,
+
+    /// Who the client is, as decided by the backend when the client authenticated.
+    pub identity: Option<String>,
+    pub role: ClientRole,
+
+    /// What has been measured of the client's network link, for backends that want to adapt
+    /// what they draw to it.
+    pub link: LinkStats,
+}
+
+/// A snapshot of what the server knows about a client.
+///
+/// Fields negotiated during the handshake are `None` until the client has gotten that far.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub addr: PeerAddr,
+    pub listener: Option<ListenAddr>,
+
+    /// The security type the client authenticated with.
+    pub security_type: Option<SecurityType>,
+    pub identity: Option<String>,
+    pub role: ClientRole,
+    pub version: Option<ProtoVersion>,
+
+    /// Whether the client agreed to share the server with other clients.
+    pub shared: Option<bool>,
+
+    /// The pixel format updates are sent to the client in.
+    pub pixel_format: Option<PixelFormat>,
+
+    /// Encodings the client supports, in its order of preference.
+    pub encodings: Vec<EncodingType>,
+
+    /// What has been measured of the client's network link.
+    pub link: LinkStats,
+
+    pub connected_at: SystemTime,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Requests pushed to a connection from its [`ClientHandle`].
+#[derive(Debug)]
+pub(crate) enum ClientCommand {
+    Bell,
+    CutText(String),
+    Refresh,
+
+    /// The server is stopping gracefully: finish the update the client is waiting for, then
+    /// close.
+    Drain,
+}
+
+/// Handshake results and client preferences, updated by the connection as it learns them.
+#[derive(Default)]
+pub(crate) struct ClientState {
+    pub security_type: Option<SecurityType>,
+    pub authorization: Authorization,
+    pub version: Option<ProtoVersion>,
+    pub shared: Option<bool>,
+    pub pixel_format: Option<PixelFormat>,
+    pub encodings: Vec<EncodingType>,
+    pub link: LinkStats,
+}
+
+/// State shared between a connection task, the registry and any handles to the client.
+pub(crate) struct Client {
+    id: ClientId,
+    addr: PeerAddr,
+    listener: Option<ListenAddr>,
+    connected_at: SystemTime,
+    connected_instant: Instant,
+    last_activity: Mutex<Instant>,
+
+    /// Whether the connection holds one of the server's connection slots, as opposed to waiting
+    /// in the queue for one.
+    active: AtomicBool,
+
+    pub state: Mutex<ClientState>,
+    pub metrics: Counters,
+
+    /// Used to ask the connection to close.
+    close_tx: watch::Sender<Option<DisconnectReason>>,
+    cmd_tx: mpsc::UnboundedSender<ClientCommand>,
+}
+
+impl Client {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    pub fn addr(&self) -> &PeerAddr {
+        &self.addr
+    }
+
+    pub fn listener(&self) -> Option<&ListenAddr> {
+        self.listener.as_ref()
+    }
+
+    pub fn info(&self) -> ClientInfo {
+        let state = self.state.lock().unwrap();
+        ClientInfo {
+            id: self.id,
+            addr: self.addr.clone(),
+            listener: self.listener.clone(),
+            security_type: state.security_type.clone(),
+            identity: state.authorization.identity.clone(),
+            role: state.authorization.role,
+            version: state.version,
+            pixel_format: state.pixel_format.clone(),
+            shared: state.shared,
+            encodings: state.encodings.clone(),
+            link: state.link,
+            connected_at: self.connected_at,
+            bytes_in: self.metrics.bytes_in(),
+            bytes_out: self.metrics.bytes_out(),
+        }
+    }
+
+    pub fn context(&self) -> ClientContext {
+        let state = self.state.lock().unwrap();
+        ClientContext {
+            id: self.id,
+            addr: self.addr.clone(),
+            listener: self.listener.clone(),
+            identity: state.authorization.identity.clone(),
+            role: state.authorization.role,
+            link: state.link,
+        }
+    }
+
+    /// Record that the client sent input or requested an update.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    pub fn activate(&self) {
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn close(&self, reason: DisconnectReason) {
+        self.close_tx.send_replace(Some(reason));
+    }
+}
+
+/// A handle to a connected client.
+///
+/// Handles stay valid after the client disconnects, but any request made through them fails with
+/// [`ServerError::ClientDisconnected`].
+#[derive(Clone)]
+pub struct ClientHandle {
+    client: Arc<Client>,
+}
+
+impl ClientHandle {
+    pub fn id(&self) -> ClientId {
+        self.client.id
+    }
+
+    /// Returns a snapshot of the client's current state.
+    pub fn info(&self) -> ClientInfo {
+        self.client.info()
+    }
+
+    /// Returns a snapshot of the client's metrics.
+    pub fn metrics(&self) -> ConnectionMetrics {
+        self.client.metrics.snapshot()
+    }
+
+    /// Returns true if the client is still connected.
+    pub fn is_connected(&self) -> bool {
+        !self.client.cmd_tx.is_closed()
+    }
+
+    /// Disconnect the client.
+    pub fn disconnect(&self) -> Result<(), ServerError> {
+        self.check_connected()?;
+        info!(client = %self.client.id, peer = %self.client.addr, "disconnecting client");
+        self.client.close(DisconnectReason::Disconnected);
+        Ok(())
+    }
+
+    /// Ring the client's bell.
+    pub fn bell(&self) -> Result<(), ServerError> {
+        self.send(ClientCommand::Bell)
+    }
+
+    /// Replace the client's clipboard contents with `text`.
+    pub fn cut_text(&self, text: String) -> Result<(), ServerError> {
+        self.send(ClientCommand::CutText(text))
+    }
+
+    /// Send the client a full framebuffer update, regardless of what it has asked for.
+    pub fn refresh(&self) -> Result<(), ServerError> {
+        self.send(ClientCommand::Refresh)
+    }
+
+    fn check_connected(&self) -> Result<(), ServerError> {
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(ServerError::ClientDisconnected(self.client.id))
+        }
+    }
+
+    fn send(&self, cmd: ClientCommand) -> Result<(), ServerError> {
+        self.client
+            .cmd_tx
+            .send(cmd)
+            .map_err(|_| ServerError::ClientDisconnected(self.client.id))
+    }
+}
+
+/// The receiving ends of a client's channels, owned by its connection task.
+pub(crate) struct ClientChannels {
+    pub close_rx: watch::Receiver<Option<DisconnectReason>>,
+    pub cmd_rx: mpsc::UnboundedReceiver<ClientCommand>,
+}
+
+/// The set of open client connections, including those queued waiting for a slot.
+#[derive(Default)]
+pub(crate) struct Connections {
+    next_id: AtomicU64,
+    entries: Mutex<BTreeMap<ClientId, Arc<Client>>>,
+    totals: Totals,
+}
+
+impl Connections {
+    /// Track a new connection.
+    pub fn register(
+        &self,
+        addr: PeerAddr,
+        listener: Option<ListenAddr>,
+    ) -> (Arc<Client>, ClientChannels) {
+        let id = ClientId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (close_tx, close_rx) = watch::channel(None);
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id,
+            addr,
+            listener,
+            connected_at: SystemTime::now(),
+            connected_instant: now,
+            last_activity: Mutex::new(now),
+            active: AtomicBool::new(false),
+            state: Mutex::new(ClientState::default()),
+            metrics: Counters::default(),
+            close_tx,
+            cmd_tx,
+        });
+        self.entries.lock().unwrap().insert(id, client.clone());
+        (client, ClientChannels { close_rx, cmd_rx })
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        if let Some(client) = self.entries.lock().unwrap().remove(&id) {
+            self.totals.record_closed(&client.metrics);
+        }
+    }
+
+    pub fn metrics(&self) -> ServerMetrics {
+        let entries = self.entries.lock().unwrap();
+        let active = entries.values().filter(|c| c.is_active()).count();
+        let connections = entries
+            .iter()
+            .map(|(id, c)| (*id, c.metrics.snapshot()))
+            .collect();
+        self.totals
+            .snapshot(self.next_id.load(Ordering::Relaxed), active, connections)
+    }
+
+    pub fn clear(&self) {
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        for client in entries.values() {
+            self.totals.record_closed(&client.metrics);
+        }
+    }
+
+    pub fn count(&self, active: bool) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.values().filter(|c| c.is_active() == active).count()
+    }
+
+    /// Returns handles to every client that holds a connection slot.
+    pub fn handles(&self) -> Vec<ClientHandle> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .values()
+            .filter(|c| c.is_active())
+            .map(|c| ClientHandle { client: c.clone() })
+            .collect()
+    }
+
+    pub fn handle(&self, id: ClientId) -> Option<ClientHandle> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&id)
+            .filter(|c| c.is_active())
+            .map(|c| ClientHandle { client: c.clone() })
+    }
+
+    /// Ask the active connection chosen by `policy` to close, to make room for a new one.
+    pub fn evict(&self, policy: OverflowPolicy) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let active = entries.values().filter(|c| c.is_active());
+        let victim = match policy {
+            OverflowPolicy::DisconnectOldest => active.min_by_key(|c| c.connected_instant),
+            OverflowPolicy::DisconnectIdle => {
+                active.min_by_key(|c| *c.last_activity.lock().unwrap())
+            }
+            OverflowPolicy::Refuse | OverflowPolicy::Queue => None,
+        };
+
+        match victim {
+            Some(c) => {
+                info!(client = %c.id, peer = %c.addr, ?policy, "evicting client");
+                c.close(DisconnectReason::Evicted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ask every connection other than `id` to close, returning how many were asked.
+    pub fn close_others(&self, id: ClientId, reason: DisconnectReason) -> usize {
+        let entries = self.entries.lock().unwrap();
+        let others: Vec<_> = entries.values().filter(|c| c.id != id).collect();
+        for c in &others {
+            c.close(reason.clone());
+        }
+        others.len()
+    }
+
+    pub fn close_all(&self, reason: DisconnectReason) {
+        for c in self.entries.lock().unwrap().values() {
+            c.close(reason.clone());
+        }
+    }
+
+    /// Ask every connection to close once it has answered the update request it is waiting on.
+    /// Connections still queued for a slot have nothing to finish, so are closed straight away.
+    pub fn drain_all(&self) {
+        for c in self.entries.lock().unwrap().values() {
+            if !c.is_active() || c.cmd_tx.send(ClientCommand::Drain).is_err() {
+                c.close(DisconnectReason::ServerShutdown);
+            }
+        }
+    }
+}
+
+/// Wraps a client's stream to count the bytes sent and received.
+pub(crate) struct CountingStream<T> {
+    inner: T,
+    client: Arc<Client>,
+}
+
+impl<T> CountingStream<T> {
+    pub fn new(inner: T, client: Arc<Client>) -> Self {
+        Self { inner, client }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.client.metrics.record_read(n);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.client.metrics.record_write(n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
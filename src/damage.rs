@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Damage notifications
+//!
+//! Backends that know when their framebuffer changes can report it through a
+//! [`DamageNotifier`], instead of the server polling them for frames. Clients waiting for an
+//! update are then answered as soon as the damage is reported.
+
+use futures::future;
+use tokio::sync::broadcast;
+
+use crate::region::{Rect, Region};
+
+/// How many notifications a connection may fall behind by before it loses track of what was
+/// damaged, and treats the whole framebuffer as changed.
+const CAPACITY: usize = 256;
+
+/// Reports changes to the framebuffer to the connections waiting on it.
+///
+/// The one from [`VncServer::damage_notifier`](crate::server::VncServer::damage_notifier) reaches
+/// every connection of the server. A backend can instead make its own and return it from
+/// [`Server::damage_notifier`](crate::server::Server::damage_notifier), to reach only the
+/// connections using that backend. It can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct DamageNotifier {
+    tx: broadcast::Sender<Rect>,
+}
+
+impl Default for DamageNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DamageNotifier {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        DamageNotifier { tx }
+    }
+
+    /// Report that `rect` of the framebuffer changed.
+    pub fn damage(&self, rect: Rect) {
+        // This only fails if no clients are connected, in which case there's nobody to tell.
+        let _ = self.tx.send(rect);
+    }
+
+    pub(crate) fn subscribe(&self) -> DamageReceiver {
+        DamageReceiver {
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+/// A connection's subscription to damage notifications.
+pub(crate) struct DamageReceiver {
+    rx: broadcast::Receiver<Rect>,
+}
+
+impl DamageReceiver {
+    /// Wait for damage to be reported, returning everything reported since the last call, or
+    /// `None` if notifications were missed and any part of the framebuffer may have changed.
+    pub async fn recv(&mut self) -> Option<Region> {
+        let mut region = Region::new();
+        match self.rx.recv().await {
+            Ok(rect) => region.add(rect),
+            Err(broadcast::error::RecvError::Lagged(_)) => return None,
+            // The server holds a sender for as long as it exists.
+            Err(broadcast::error::RecvError::Closed) => future::pending().await,
+        }
+        loop {
+            match self.rx.try_recv() {
+                Ok(rect) => region.add(rect),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => return None,
+                Err(_) => return Some(region),
+            }
+        }
+    }
+}
@@ -4,6 +4,8 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+use flate2::{Compress, Compression, FlushCompress};
+
 use crate::{
     pixel_formats::rgb_888,
     rfb::{PixelFormat, Position, Resolution},
@@ -11,7 +13,7 @@ use crate::{
 
 use EncodingType::*;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[allow(unused)]
 pub enum EncodingType {
     Raw,
@@ -27,6 +29,8 @@ pub enum EncodingType {
     JPEG,
     Zlib,
     CursorWithAlpha,
+    FencePseudo,
+    ContinuousUpdatesPseudo,
     Other(i32),
 }
 
@@ -59,6 +63,8 @@ impl From<EncodingType> for i32 {
             JPEG => 21,
             Zlib => 6,
             CursorWithAlpha => -314,
+            FencePseudo => -312,
+            ContinuousUpdatesPseudo => -313,
             Other(n) => n,
         }
     }
@@ -80,6 +86,8 @@ impl From<i32> for EncodingType {
             21 => JPEG,
             6 => Zlib,
             -314 => CursorWithAlpha,
+            -312 => FencePseudo,
+            -313 => ContinuousUpdatesPseudo,
             v => EncodingType::Other(v),
         }
     }
@@ -112,11 +120,126 @@ impl Encoding for RawEncoding {
         assert!(output.is_rgb_888());
 
         Box::new(Self {
-            pixels: rgb_888::transform(&self.pixels, &input, &output),
+            pixels: rgb_888::transform(&self.pixels, input, output),
+        })
+    }
+}
+
+/// Section 7.7.2
+///
+/// The rectangle is filled by copying the one of the same size at (`src_x`, `src_y`) of the
+/// framebuffer the client already has.
+pub struct CopyRectEncoding {
+    bytes: Vec<u8>,
+}
+
+impl CopyRectEncoding {
+    pub fn new(src_x: u16, src_y: u16) -> Self {
+        let mut bytes = Vec::with_capacity(4);
+        bytes.extend_from_slice(&src_x.to_be_bytes());
+        bytes.extend_from_slice(&src_y.to_be_bytes());
+        Self { bytes }
+    }
+
+    /// Parse the source position out of an encoded CopyRect rectangle.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [x0, x1, y0, y1] => Some(Self::new(
+                u16::from_be_bytes([*x0, *x1]),
+                u16::from_be_bytes([*y0, *y1]),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn src_x(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    pub fn src_y(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+}
+
+impl Encoding for CopyRectEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::CopyRect
+    }
+
+    fn encode(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        // There are no pixels to translate.
+        Box::new(Self {
+            bytes: self.bytes.clone(),
         })
     }
 }
 
+/// Raw pixel data compressed with zlib.
+///
+/// Every Zlib rectangle sent to a client continues the same compression stream. The pixels are
+/// compressed in the client's pixel format, so these rectangles are built after any
+/// transformation, and transforming one leaves it as it is.
+pub struct ZlibEncoding {
+    bytes: Vec<u8>,
+}
+
+impl Encoding for ZlibEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::Zlib
+    }
+
+    fn encode(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        Box::new(Self {
+            bytes: self.bytes.clone(),
+        })
+    }
+}
+
+/// The zlib stream shared by all the Zlib rectangles sent to one client.
+pub(crate) struct ZlibStream {
+    compress: Compress,
+}
+
+impl ZlibStream {
+    /// Start a stream compressing at `level`, from 0 (none) to 9 (best).
+    pub fn new(level: u32) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level), true),
+        }
+    }
+
+    /// Compress `pixels`, flushing the stream so the client can decode the rectangle on its own.
+    pub fn encode(&mut self, pixels: &[u8]) -> ZlibEncoding {
+        let mut out = Vec::with_capacity(pixels.len() / 2 + 64);
+        let mut input = pixels;
+        loop {
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut out, FlushCompress::Sync)
+                .expect("zlib stream is never finished");
+            input = &input[(self.compress.total_in() - before) as usize..];
+            // The flush is done once there was room to spare for its output.
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(1024));
+        }
+
+        let mut bytes = Vec::with_capacity(4 + out.len());
+        bytes.extend_from_slice(&(out.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&out);
+        ZlibEncoding { bytes }
+    }
+}
+
 #[allow(dead_code)]
 struct RREncoding {
     background_pixel: Pixel,
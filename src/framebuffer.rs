@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! An owned framebuffer for backends to draw into
+//!
+//! A [`Framebuffer`] holds pixels in a fixed [`PixelFormat`], and records which parts of it were
+//! drawn to. Given a [`DamageNotifier`], every change is also reported to the server, so clients
+//! waiting for an update get it without the backend doing anything more than drawing.
+
+use thiserror::Error;
+
+use crate::damage::DamageNotifier;
+use crate::encodings::RawEncoding;
+use crate::region::{Rect, Region};
+use crate::rfb::{FramebufferUpdate, PixelFormat, Rectangle};
+
+#[derive(Debug, Error)]
+pub enum FramebufferError {
+    #[error("stride of {stride} bytes is too short for {width} pixels of {bytes_per_pixel} bytes")]
+    StrideTooShort {
+        stride: usize,
+        width: u16,
+        bytes_per_pixel: usize,
+    },
+
+    #[error("expected {expected} bytes of pixel data, got {actual}")]
+    WrongLength { expected: usize, actual: usize },
+}
+
+/// Pixels of a screen, in a fixed pixel format, with a record of what changed.
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    /// Bytes from the start of one row to the start of the next.
+    stride: usize,
+    format: PixelFormat,
+    bytes_per_pixel: usize,
+    pixels: Vec<u8>,
+    damage: Region,
+    notifier: Option<DamageNotifier>,
+}
+
+impl Framebuffer {
+    /// Create a framebuffer of `width` by `height` pixels, all zero.
+    pub fn new(width: u16, height: u16, format: PixelFormat) -> Self {
+        let bytes_per_pixel = bytes_per_pixel(&format);
+        let stride = width as usize * bytes_per_pixel;
+        Framebuffer {
+            width,
+            height,
+            stride,
+            format,
+            bytes_per_pixel,
+            pixels: vec![0; stride * height as usize],
+            damage: Region::new(),
+            notifier: None,
+        }
+    }
+
+    /// Create a framebuffer from existing pixel data, whose rows are `stride` bytes apart.
+    pub fn from_pixels(
+        width: u16,
+        height: u16,
+        stride: usize,
+        format: PixelFormat,
+        pixels: Vec<u8>,
+    ) -> Result<Self, FramebufferError> {
+        let bytes_per_pixel = bytes_per_pixel(&format);
+        if stride < width as usize * bytes_per_pixel {
+            return Err(FramebufferError::StrideTooShort {
+                stride,
+                width,
+                bytes_per_pixel,
+            });
+        }
+        let expected = stride * height as usize;
+        if pixels.len() != expected {
+            return Err(FramebufferError::WrongLength {
+                expected,
+                actual: pixels.len(),
+            });
+        }
+        Ok(Framebuffer {
+            width,
+            height,
+            stride,
+            format,
+            bytes_per_pixel,
+            pixels,
+            damage: Region::new(),
+            notifier: None,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.format
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bytes_per_pixel
+    }
+
+    /// The whole framebuffer as a rectangle.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// The raw pixel data, with rows `stride` bytes apart.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Report every future change to the server's connections through `notifier`.
+    pub fn set_damage_notifier(&mut self, notifier: DamageNotifier) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Record that `rect` changed, for changes made outside of the drawing methods.
+    pub fn damage(&mut self, rect: Rect) {
+        let Some(rect) = rect.intersection(&self.bounds()) else {
+            return;
+        };
+        self.damage.add(rect);
+        if let Some(notifier) = &self.notifier {
+            notifier.damage(rect);
+        }
+    }
+
+    /// Returns everything that changed since the last call.
+    pub fn take_damage(&mut self) -> Region {
+        self.damage.take()
+    }
+
+    fn offset(&self, x: u16, y: u16) -> usize {
+        y as usize * self.stride + x as usize * self.bytes_per_pixel
+    }
+
+    /// Copy the pixels of `rect` out of the framebuffer, with rows packed together. Parts of
+    /// `rect` outside the framebuffer are left out.
+    pub fn read_rect(&self, rect: Rect) -> Vec<u8> {
+        let Some(rect) = rect.intersection(&self.bounds()) else {
+            return Vec::new();
+        };
+        let row_len = rect.width as usize * self.bytes_per_pixel;
+        let mut out = Vec::with_capacity(row_len * rect.height as usize);
+        for y in rect.y..rect.bottom() as u16 {
+            let start = self.offset(rect.x, y);
+            out.extend_from_slice(&self.pixels[start..][..row_len]);
+        }
+        out
+    }
+
+    /// Draw `src`, an image `width` pixels wide with rows `src_stride` bytes apart, with its top
+    /// left corner at (`x`, `y`). The last row needn't be padded out to the stride. Anything
+    /// falling outside the framebuffer is dropped.
+    pub fn blit(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        src: &[u8],
+        src_stride: usize,
+    ) -> Result<(), FramebufferError> {
+        let src_row_len = width as usize * self.bytes_per_pixel;
+        if src_stride < src_row_len {
+            return Err(FramebufferError::StrideTooShort {
+                stride: src_stride,
+                width,
+                bytes_per_pixel: self.bytes_per_pixel,
+            });
+        }
+        if src_row_len == 0 {
+            return Ok(());
+        }
+        let rows = src.len().div_ceil(src_stride);
+        let unpadded = rows.saturating_sub(1) * src_stride + src_row_len;
+        if src.len() != unpadded && src.len() != rows * src_stride {
+            return Err(FramebufferError::WrongLength {
+                expected: unpadded,
+                actual: src.len(),
+            });
+        }
+
+        let height = rows.min(u16::MAX as usize) as u16;
+        let Some(dst) = Rect::new(x, y, width, height).intersection(&self.bounds()) else {
+            return Ok(());
+        };
+        let row_len = dst.width as usize * self.bytes_per_pixel;
+        for row in 0..dst.height as usize {
+            let start = self.offset(dst.x, dst.y + row as u16);
+            self.pixels[start..][..row_len].copy_from_slice(&src[row * src_stride..][..row_len]);
+        }
+        self.damage(dst);
+        Ok(())
+    }
+
+    /// Fill `rect` with `pixel`, a single pixel in the framebuffer's format.
+    pub fn fill_rect(&mut self, rect: Rect, pixel: &[u8]) -> Result<(), FramebufferError> {
+        if pixel.len() != self.bytes_per_pixel {
+            return Err(FramebufferError::WrongLength {
+                expected: self.bytes_per_pixel,
+                actual: pixel.len(),
+            });
+        }
+        let Some(rect) = rect.intersection(&self.bounds()) else {
+            return Ok(());
+        };
+        let row_len = rect.width as usize * self.bytes_per_pixel;
+        for y in rect.y..rect.bottom() as u16 {
+            let start = self.offset(rect.x, y);
+            for dst in self.pixels[start..][..row_len].chunks_exact_mut(self.bytes_per_pixel) {
+                dst.copy_from_slice(pixel);
+            }
+        }
+        self.damage(rect);
+        Ok(())
+    }
+
+    /// Copy the pixels of `src` so its top left corner is at (`x`, `y`). The source and
+    /// destination may overlap, as when scrolling.
+    pub fn copy_rect(&mut self, src: Rect, x: u16, y: u16) {
+        let bounds = self.bounds();
+        let Some(clipped) = src.intersection(&bounds) else {
+            return;
+        };
+        // Move the destination along with any clipping of the source...
+        let moved = Rect::new(
+            x.saturating_add(clipped.x - src.x),
+            y.saturating_add(clipped.y - src.y),
+            clipped.width,
+            clipped.height,
+        );
+        // ...then clip the destination, and the source with it.
+        let Some(dst) = moved.intersection(&bounds) else {
+            return;
+        };
+        let src_x = clipped.x + (dst.x - moved.x);
+        let src_y = clipped.y + (dst.y - moved.y);
+
+        let row_len = dst.width as usize * self.bytes_per_pixel;
+        let copy_row = |fb: &mut Self, row: u16| {
+            let from = fb.offset(src_x, src_y + row);
+            let to = fb.offset(dst.x, dst.y + row);
+            fb.pixels.copy_within(from..from + row_len, to);
+        };
+        // Copy rows in the order that never overwrites a row before it has been read.
+        if dst.y <= src_y {
+            (0..dst.height).for_each(|row| copy_row(self, row));
+        } else {
+            (0..dst.height).rev().for_each(|row| copy_row(self, row));
+        }
+        self.damage(dst);
+    }
+
+    /// Build an update of `area` from the framebuffer, as a single Raw rectangle, for answering
+    /// [`Server::get_framebuffer_update`](crate::server::Server::get_framebuffer_update).
+    pub fn update(&self, area: Rect) -> FramebufferUpdate {
+        let Some(area) = area.intersection(&self.bounds()) else {
+            return FramebufferUpdate::new(vec![]);
+        };
+        let r = Rectangle::new(
+            area.x,
+            area.y,
+            area.width,
+            area.height,
+            Box::new(RawEncoding::new(self.read_rect(area))),
+        );
+        FramebufferUpdate::new(vec![r])
+    }
+}
+
+fn bytes_per_pixel(format: &PixelFormat) -> usize {
+    (format.bits_per_pixel as usize).div_ceil(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framebuffer, FramebufferError};
+    use crate::pixel_formats::fourcc;
+    use crate::region::Rect;
+
+    fn framebuffer() -> Framebuffer {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        Framebuffer::new(8, 8, pf)
+    }
+
+    #[test]
+    fn test_drawing_records_damage() {
+        let mut fb = framebuffer();
+        fb.fill_rect(Rect::new(6, 6, 4, 4), &[1, 2, 3, 4]).unwrap();
+        assert_eq!(fb.take_damage().rects(), &[Rect::new(6, 6, 2, 2)]);
+        assert_eq!(fb.read_rect(Rect::new(7, 7, 1, 1)), vec![1, 2, 3, 4]);
+        assert!(fb.take_damage().is_empty());
+
+        let src: Vec<u8> = (0..2 * 2 * 4).collect();
+        fb.blit(1, 2, 2, &src, 2 * 4).unwrap();
+        assert_eq!(fb.take_damage().rects(), &[Rect::new(1, 2, 2, 2)]);
+        assert_eq!(fb.read_rect(Rect::new(1, 2, 2, 2)), src);
+    }
+
+    #[test]
+    fn test_blit_checks_source() {
+        let mut fb = framebuffer();
+
+        // Rows too short for the width are refused...
+        let res = fb.blit(0, 0, 2, &[0; 16], 4);
+        assert!(matches!(res, Err(FramebufferError::StrideTooShort { .. })));
+
+        // ...as is a final row cut short...
+        let res = fb.blit(0, 0, 2, &[0; 16], 12);
+        assert!(matches!(
+            res,
+            Err(FramebufferError::WrongLength {
+                expected: 20,
+                actual: 16
+            })
+        ));
+        assert!(fb.take_damage().is_empty());
+
+        // ...but one without padding after it is drawn.
+        let src: Vec<u8> = (0..12 + 8).collect();
+        fb.blit(0, 0, 2, &src, 12).unwrap();
+        assert_eq!(fb.take_damage().rects(), &[Rect::new(0, 0, 2, 2)]);
+        assert_eq!(fb.read_rect(Rect::new(0, 1, 2, 1)), src[12..]);
+    }
+
+    #[test]
+    fn test_fill_rect_checks_pixel() {
+        let mut fb = framebuffer();
+        let res = fb.fill_rect(Rect::new(0, 0, 2, 2), &[1, 2, 3]);
+        assert!(matches!(
+            res,
+            Err(FramebufferError::WrongLength {
+                expected: 4,
+                actual: 3
+            })
+        ));
+        assert!(fb.take_damage().is_empty());
+        assert_eq!(fb.read_rect(Rect::new(0, 0, 1, 1)), vec![0; 4]);
+    }
+
+    #[test]
+    fn test_copy_rect_overlapping() {
+        let mut fb = framebuffer();
+        for y in 0..8 {
+            fb.fill_rect(Rect::new(0, y, 8, 1), &[y as u8; 4]).unwrap();
+        }
+        fb.take_damage();
+
+        // Scroll up by two rows: each row now holds what was two below it.
+        fb.copy_rect(Rect::new(0, 2, 8, 6), 0, 0);
+        assert_eq!(fb.take_damage().rects(), &[Rect::new(0, 0, 8, 6)]);
+        assert_eq!(fb.read_rect(Rect::new(0, 0, 1, 1)), vec![2; 4]);
+        assert_eq!(fb.read_rect(Rect::new(0, 5, 1, 1)), vec![7; 4]);
+
+        // And back down, clipped at the bottom edge.
+        fb.copy_rect(Rect::new(0, 0, 8, 8), 0, 1);
+        assert_eq!(fb.read_rect(Rect::new(0, 1, 1, 1)), vec![2; 4]);
+        assert_eq!(fb.read_rect(Rect::new(0, 7, 1, 1)), vec![6; 4]);
+    }
+}
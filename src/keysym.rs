@@ -178,7 +178,7 @@ impl TryFrom<u32> for KeySym {
             KEYSYM_RIGHT => Ok(Right),
             KEYSYM_DOWN => Ok(Down),
 
-            f if (f >= KEYSYM_F1 && f <= KEYSYM_F12) => {
+            f if (KEYSYM_F1..=KEYSYM_F12).contains(&f) => {
                 let n = f - KEYSYM_F1 + 1;
                 // TODO: handle cast
                 Ok(FunctionKey(n as u8))
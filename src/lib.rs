@@ -4,8 +4,23 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+pub mod clients;
+pub mod damage;
 pub mod encodings;
+pub mod framebuffer;
 pub mod keysym;
+pub mod link;
+pub mod listener;
+pub mod manager;
+pub mod metrics;
 pub mod pixel_formats;
+pub mod proxy;
+pub mod region;
+pub mod repeater;
 pub mod rfb;
 pub mod server;
+#[cfg(test)]
+mod testing;
+pub mod tiles;
+mod update;
+mod websocket;
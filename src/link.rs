@@ -0,0 +1,406 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Link measurements and encoding selection
+//!
+//! Each connection estimates the bandwidth and round-trip time of its link from the updates it
+//! sends: clients ask for the next update once they have received the last one, so the time from
+//! starting to write an update to the next request arriving covers both the transfer and a round
+//! trip. Small updates take next to no time to transfer, so they time the round trip, which is
+//! then taken off the time large ones took to leave the transfer.
+//!
+//! Only when the write started is used. A write returns once the update is in the socket's send
+//! buffer, long before it has crossed a slow link, so timing from its end would count most of the
+//! transfer as round trip, and overestimate the bandwidth.
+//!
+//! Pixel data is then sent in whichever encoding suits the link, out of those the client accepts.
+//! On a fast, close link, compressing costs more time than it saves, so pixels are sent raw. On a
+//! slow one, pixels are compressed with zlib after dropping the low bits of each colour channel,
+//! which loses some colour depth but compresses far better. In between, pixels are sent in
+//! whichever of raw and zlib the client prefers. The choice is revisited with every update.
+
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::encodings::{EncodingType, ZlibStream};
+use crate::rfb::{ColorSpecification, FramebufferUpdate, PixelFormat, Rectangle};
+
+/// How much each new sample moves an estimate.
+const SMOOTHING: f64 = 0.25;
+
+/// Updates smaller than this are dominated by the round trip, so say little about bandwidth.
+const MIN_BANDWIDTH_SAMPLE: usize = 16 * 1024;
+
+/// Links at least this fast, in bytes per second, with a round trip no longer than
+/// [`FAST_LINK_RTT`], are sent raw pixels...
+const FAST_LINK: f64 = 12.5e6;
+const FAST_LINK_RTT: Duration = Duration::from_millis(5);
+
+/// ...until they slow to below this, so the choice doesn't flip back and forth near the limit.
+const FAST_LINK_MIN: f64 = FAST_LINK / 2.0;
+
+/// Links slower than this are sent pixels with reduced colour depth, until they speed up past
+/// twice this.
+const SLOW_LINK: f64 = 1.25e6;
+
+/// The bits kept of each colour channel of pixels sent with reduced colour depth.
+const LOSSY_BITS: u32 = 4;
+
+/// The zlib compression level for a stream started on a link slower than [`SLOW_LINK`], and on a
+/// faster one. Deflate allows the level to change after a flush, but flate2's default backend has
+/// no way to do so, so a stream keeps its level for as long as it lasts.
+const ZLIB_LEVEL_SLOW: u32 = 9;
+const ZLIB_LEVEL: u32 = 6;
+
+/// Estimates of a client's network link.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    /// Bytes per second the link carries to the client, once an update large enough to measure
+    /// it has been sent.
+    pub bandwidth: Option<f64>,
+
+    /// The time from a small update being sent to the client's next request arriving, including
+    /// any time the client takes to draw the update.
+    pub rtt: Option<Duration>,
+}
+
+impl LinkStats {
+    fn is_fast(&self, threshold: f64) -> bool {
+        match (self.bandwidth, self.rtt) {
+            (Some(bandwidth), Some(rtt)) => bandwidth >= threshold && rtt <= FAST_LINK_RTT,
+            _ => false,
+        }
+    }
+}
+
+/// An update that has been written, awaiting the client's next request.
+struct InFlight {
+    started: Instant,
+    bytes: usize,
+}
+
+/// Measures a connection's link, and encodes its updates to suit it.
+pub(crate) struct LinkState {
+    stats: LinkStats,
+    in_flight: Option<InFlight>,
+
+    /// When the last update sent should have finished crossing the link.
+    drained_at: Option<Instant>,
+
+    /// The encoding pixel data is currently sent in, and whether colour depth is reduced first.
+    encoding: EncodingType,
+    lossy: bool,
+    zlib: Option<ZlibStream>,
+}
+
+impl LinkState {
+    pub fn new() -> Self {
+        LinkState {
+            stats: LinkStats::default(),
+            in_flight: None,
+            drained_at: None,
+            encoding: EncodingType::Raw,
+            lossy: false,
+            zlib: None,
+        }
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Record an update of `bytes` having been written, starting at `started`.
+    pub fn sent(&mut self, started: Instant, bytes: usize) {
+        self.drained_at = self
+            .stats
+            .bandwidth
+            .map(|b| started + Duration::from_secs_f64(bytes as f64 / b));
+        self.in_flight = Some(InFlight { started, bytes });
+    }
+
+    /// When the last update sent should have reached the client, going by the bandwidth measured.
+    pub fn drained_at(&self) -> Option<Instant> {
+        self.drained_at
+    }
+
+    /// Record an update request arriving at `at`, completing the measurement of the last update
+    /// sent, if any.
+    pub fn requested(&mut self, at: Instant) {
+        let Some(last) = self.in_flight.take() else {
+            return;
+        };
+        let elapsed = at.saturating_duration_since(last.started);
+        if last.bytes < MIN_BANDWIDTH_SAMPLE {
+            self.stats.rtt = Some(smooth_duration(self.stats.rtt, elapsed));
+            return;
+        }
+
+        // Until a round trip has been timed, the whole time counts as transfer, which can only
+        // underestimate the bandwidth.
+        let transfer = elapsed
+            .saturating_sub(self.stats.rtt.unwrap_or_default())
+            .max(Duration::from_millis(1));
+        let bandwidth = last.bytes as f64 / transfer.as_secs_f64();
+        self.stats.bandwidth = Some(smooth(self.stats.bandwidth, bandwidth));
+    }
+
+    /// Choose the encoding for pixel data, given the encodings the client accepts in its order of
+    /// preference.
+    pub fn choose(&mut self, accepted: &[EncodingType]) -> EncodingType {
+        let preferred = accepted
+            .iter()
+            .copied()
+            .find(|e| matches!(e, EncodingType::Raw | EncodingType::Zlib))
+            .unwrap_or(EncodingType::Raw);
+        let (choice, lossy) = match self.stats.bandwidth {
+            _ if !accepted.contains(&EncodingType::Zlib) => (EncodingType::Raw, false),
+            // Until there's a measurement, go with the client's preference.
+            None => (preferred, false),
+            Some(bandwidth) => {
+                let fast = match self.encoding {
+                    EncodingType::Raw => FAST_LINK_MIN,
+                    _ => FAST_LINK,
+                };
+                let slow = match self.lossy {
+                    true => SLOW_LINK * 2.0,
+                    false => SLOW_LINK,
+                };
+                if self.stats.is_fast(fast) {
+                    (EncodingType::Raw, false)
+                } else if bandwidth < slow {
+                    (EncodingType::Zlib, true)
+                } else {
+                    (preferred, false)
+                }
+            }
+        };
+
+        if (choice, lossy) != (self.encoding, self.lossy) {
+            debug!(
+                from = ?self.encoding,
+                to = ?choice,
+                lossy,
+                stats = ?self.stats,
+                "switching encoding"
+            );
+            self.encoding = choice;
+            self.lossy = lossy;
+        }
+        choice
+    }
+
+    /// Encode the Raw rectangles of `fbu`, already in the client's pixel format `format`, in the
+    /// encoding chosen for the client.
+    pub fn encode(
+        &mut self,
+        fbu: FramebufferUpdate,
+        accepted: &[EncodingType],
+        format: &PixelFormat,
+    ) -> FramebufferUpdate {
+        if self.choose(accepted) != EncodingType::Zlib {
+            return fbu;
+        }
+        let mask = match self.lossy {
+            true => lossy_mask(format),
+            false => None,
+        };
+        let stats = self.stats;
+        let zlib = self.zlib.get_or_insert_with(|| {
+            let slow = stats.bandwidth.is_some_and(|b| b < SLOW_LINK);
+            ZlibStream::new(if slow { ZLIB_LEVEL_SLOW } else { ZLIB_LEVEL })
+        });
+
+        let rects = fbu
+            .into_rectangles()
+            .into_iter()
+            .map(|r| {
+                if r.encoding_type() != EncodingType::Raw {
+                    return r;
+                }
+                let rect = r.rect();
+                let data = match &mask {
+                    Some(mask) => zlib.encode(&apply_mask(r.data().encode(), mask)),
+                    None => zlib.encode(r.data().encode()),
+                };
+                Rectangle::new(rect.x, rect.y, rect.width, rect.height, Box::new(data))
+            })
+            .collect();
+        FramebufferUpdate::new(rects)
+    }
+}
+
+/// The bytes to AND each pixel of `format` with to keep only the top [`LOSSY_BITS`] of each colour
+/// channel, or `None` for formats that don't use colour channels.
+fn lossy_mask(format: &PixelFormat) -> Option<Vec<u8>> {
+    let ColorSpecification::ColorFormat(cf) = &format.color_spec else {
+        return None;
+    };
+    let mut mask = u32::MAX;
+    for (max, shift) in [
+        (cf.red_max, cf.red_shift),
+        (cf.green_max, cf.green_shift),
+        (cf.blue_max, cf.blue_shift),
+    ] {
+        let bits = u16::BITS - max.leading_zeros();
+        let dropped = bits.saturating_sub(LOSSY_BITS);
+        mask &= !(((1u32 << dropped) - 1)
+            .checked_shl(shift.into())
+            .unwrap_or(0));
+    }
+    let bytes_per_pixel = (format.bits_per_pixel as usize).div_ceil(8).clamp(1, 4);
+    Some(match format.big_endian {
+        true => mask.to_be_bytes()[4 - bytes_per_pixel..].to_vec(),
+        false => mask.to_le_bytes()[..bytes_per_pixel].to_vec(),
+    })
+}
+
+fn apply_mask(pixels: &[u8], mask: &[u8]) -> Vec<u8> {
+    let mut out = pixels.to_vec();
+    for pixel in out.chunks_exact_mut(mask.len()) {
+        pixel.iter_mut().zip(mask).for_each(|(b, m)| *b &= m);
+    }
+    out
+}
+
+fn smooth(estimate: Option<f64>, sample: f64) -> f64 {
+    match estimate {
+        Some(estimate) => estimate + SMOOTHING * (sample - estimate),
+        None => sample,
+    }
+}
+
+fn smooth_duration(estimate: Option<Duration>, sample: Duration) -> Duration {
+    let secs = smooth(estimate.map(|e| e.as_secs_f64()), sample.as_secs_f64());
+    Duration::from_secs_f64(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use flate2::{Decompress, FlushDecompress};
+
+    use super::{LinkState, FAST_LINK, SLOW_LINK};
+    use crate::encodings::{EncodingType, RawEncoding};
+    use crate::pixel_formats::fourcc;
+    use crate::rfb::{FramebufferUpdate, Rectangle};
+
+    #[test]
+    fn test_choice_follows_link() {
+        let accepted = [EncodingType::Zlib, EncodingType::Raw];
+        let mut link = LinkState::new();
+        assert_eq!(link.choose(&[EncodingType::Raw]), EncodingType::Raw);
+        assert_eq!(link.choose(&accepted), EncodingType::Zlib);
+
+        // A round trip of a millisecond, timed by a small update...
+        let start = Instant::now();
+        let sent = |link: &mut LinkState, bytes: usize, ms: u64| {
+            link.sent(start, bytes);
+            link.requested(start + Duration::from_millis(ms));
+        };
+        sent(&mut link, 100, 1);
+        assert_eq!(link.stats().rtt, Some(Duration::from_millis(1)));
+
+        // ...and a megabyte delivered within a few milliseconds is a fast link...
+        sent(&mut link, 1 << 20, 11);
+        assert!(link.stats().bandwidth.unwrap() > FAST_LINK);
+        assert_eq!(link.choose(&accepted), EncodingType::Raw);
+
+        // ...and one that takes a second is slow enough to lose colour depth.
+        for _ in 0..32 {
+            sent(&mut link, 1 << 20, 1001);
+        }
+        assert_eq!(link.choose(&accepted), EncodingType::Zlib);
+        assert!(link.lossy);
+    }
+
+    #[test]
+    fn test_choice_follows_preference() {
+        // On a link neither fast nor slow, the client's preference decides.
+        let mut link = LinkState::new();
+        let start = Instant::now();
+        link.sent(start, 100);
+        link.requested(start + Duration::from_millis(1));
+        link.sent(start, 1 << 20);
+        link.requested(start + Duration::from_millis(201));
+        let bandwidth = link.stats().bandwidth.unwrap();
+        assert!((SLOW_LINK * 2.0..FAST_LINK / 2.0).contains(&bandwidth));
+
+        let raw_first = [EncodingType::Raw, EncodingType::Zlib];
+        assert_eq!(link.choose(&raw_first), EncodingType::Raw);
+        let zlib_first = [EncodingType::Zlib, EncodingType::Raw];
+        assert_eq!(link.choose(&zlib_first), EncodingType::Zlib);
+        assert!(!link.lossy);
+    }
+
+    #[test]
+    fn test_lossy_encoding() {
+        let accepted = [EncodingType::Zlib];
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut link = LinkState::new();
+        let start = Instant::now();
+        link.sent(start, 1 << 20);
+        link.requested(start + Duration::from_secs(2));
+
+        // Each colour channel keeps only its top bits.
+        let pixels = [0x12, 0x34, 0x56, 0x78].repeat(16);
+        let r = Rectangle::new(0, 0, 4, 4, Box::new(RawEncoding::new(pixels)));
+        let fbu = link.encode(FramebufferUpdate::new(vec![r]), &accepted, &pf);
+        assert!(link.lossy);
+        let data = fbu.rectangles()[0].data().encode();
+        let mut out = Vec::with_capacity(64);
+        Decompress::new(true)
+            .decompress_vec(&data[4..], &mut out, FlushDecompress::Sync)
+            .unwrap();
+        assert_eq!(&out[..4], &[0x10, 0x30, 0x50, 0x78]);
+    }
+
+    #[test]
+    fn test_bandwidth_from_request_times() {
+        let mut link = LinkState::new();
+        let start = Instant::now();
+        link.sent(start, 100);
+        link.requested(start + Duration::from_millis(10));
+
+        // A write that returns at once, into the socket's buffer, says nothing about the link:
+        // a megabyte answered a second later crossed it at about a megabyte a second, however
+        // quickly it was written.
+        link.sent(start, 1 << 20);
+        link.requested(start + Duration::from_millis(1010));
+        let bandwidth = link.stats().bandwidth.unwrap();
+        assert!((1.0e6..1.1e6).contains(&bandwidth), "{bandwidth}");
+        // The round trip is still the one timed by the small update.
+        assert_eq!(link.stats().rtt, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_zlib_stream() {
+        let accepted = [EncodingType::Zlib];
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut link = LinkState::new();
+        let mut inflate = Decompress::new(true);
+
+        // Each rectangle can be decoded as it arrives, continuing the same stream.
+        for fill in [0u8, 7] {
+            let pixels = vec![fill; 64 * 64 * 4];
+            let r = Rectangle::new(0, 0, 64, 64, Box::new(RawEncoding::new(pixels.clone())));
+            let fbu = link.encode(FramebufferUpdate::new(vec![r]), &accepted, &pf);
+            let r = &fbu.rectangles()[0];
+            assert_eq!(r.encoding_type(), EncodingType::Zlib);
+
+            let data = r.data().encode();
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert_eq!(data.len(), 4 + len);
+            assert!(len < pixels.len() / 10);
+            let mut out = Vec::with_capacity(pixels.len());
+            inflate
+                .decompress_vec(&data[4..], &mut out, FlushDecompress::Sync)
+                .unwrap();
+            assert_eq!(out, pixels);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Listening addresses
+//!
+//! A [`VncServer`](crate::server::VncServer) can listen on any number of TCP and Unix socket
+//! addresses at once. Clients record which one they arrived on, so the backend can treat them
+//! differently.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::future;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::warn;
+
+use crate::rfb::RfbStream;
+
+/// Backlog for TCP listeners, matching what the standard library uses.
+const BACKLOG: i32 = 128;
+
+/// An address the server listens on.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl fmt::Debug for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The address of a connected client.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+
+    /// A client connected over a Unix socket, which usually has no address of its own.
+    Unix(Option<PathBuf>),
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl fmt::Debug for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A bound listener.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+                // Bind IPv6 addresses to IPv6 only, so that an IPv4 listener on the same port
+                // can sit alongside.
+                if addr.is_ipv6() {
+                    socket.set_only_v6(true)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*addr).into())?;
+                socket.listen(BACKLOG)?;
+                Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+            }
+            ListenAddr::Unix(path) => Ok(Listener::Unix(UnixListener::bind(path)?, path.clone())),
+        }
+    }
+
+    /// The address the listener is bound to, with any port chosen by the OS filled in.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(l) => Ok(ListenAddr::Tcp(l.local_addr()?)),
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    /// Accept a connection, enabling TCP keepalive on it if `keepalive` is set.
+    pub async fn accept(
+        &self,
+        keepalive: Option<Duration>,
+    ) -> io::Result<(Box<dyn RfbStream>, PeerAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (sock, addr) = l.accept().await?;
+                if let Some(time) = keepalive {
+                    let keepalive = TcpKeepalive::new().with_time(time).with_interval(time);
+                    if let Err(e) = SockRef::from(&sock).set_tcp_keepalive(&keepalive) {
+                        warn!(peer = %addr, "could not enable TCP keepalive: {}", e);
+                    }
+                }
+                Ok((Box::new(sock), PeerAddr::Tcp(addr)))
+            }
+            Listener::Unix(l, _) => {
+                let (sock, addr) = l.accept().await?;
+                let addr = PeerAddr::Unix(addr.as_pathname().map(|p| p.to_path_buf()));
+                Ok((Box::new(sock), addr))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Unix sockets leave a file behind, which would stop the server binding again.
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Accept a connection on whichever of `listeners` gets one first, returning it along with the
+/// index of the listener. Waits forever if there are no listeners.
+pub(crate) async fn accept_any(
+    listeners: &[Listener],
+    keepalive: Option<Duration>,
+) -> (io::Result<(Box<dyn RfbStream>, PeerAddr)>, usize) {
+    if listeners.is_empty() {
+        return future::pending().await;
+    }
+    let accepts = listeners.iter().map(|l| Box::pin(l.accept(keepalive)));
+    let (res, i, _) = future::select_all(accepts).await;
+    (res, i)
+}
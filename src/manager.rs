@@ -0,0 +1,434 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Running many servers in one process
+//!
+//! A [`ServerManager`] owns a set of [`VncServer`]s, each with its own backend and configuration,
+//! keyed by an id of the caller's choosing. Servers can be added and removed while the manager is
+//! running. Each one is reached either on its own listening address or by path through a
+//! WebSocket listener shared by all of them, and [`ServerManager::shutdown`] stops them together.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::rfb::RfbStream;
+use crate::server::{
+    is_connection_error, Server, ServerError, ServerFactory, VncServer, ACCEPT_BACKOFF,
+};
+use crate::websocket;
+
+/// How long a WebSocket client has to complete the upgrade before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum ManagerError {
+    #[error("a server with this id already exists")]
+    DuplicateId,
+
+    #[error("no server with this id")]
+    UnknownId,
+
+    #[error("path {0:?} is already routed to another server")]
+    DuplicatePath(String),
+
+    #[error("the manager is shutting down")]
+    ShuttingDown,
+
+    #[error("the WebSocket listener is already running")]
+    AlreadyListening,
+
+    #[error(transparent)]
+    Server(#[from] ServerError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// How clients reach a managed server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// On the server's own address, from its [`VncServerConfig`](crate::server::VncServerConfig).
+    Listen,
+
+    /// Through the manager's WebSocket listener, at this request path (for example `/vm-1`).
+    WebSocket(String),
+}
+
+/// The operations the manager needs from a server, independent of its backend type.
+#[async_trait]
+trait ManagedServer: Send + Sync {
+    async fn run(&self, listen: bool, ready: oneshot::Sender<()>) -> Result<(), ServerError>;
+    fn serve(&self, stream: Box<dyn RfbStream>, addr: SocketAddr) -> Result<(), ServerError>;
+    fn stop(&self) -> Result<(), ServerError>;
+    async fn stop_gracefully(&self, timeout: Duration) -> Result<(), ServerError>;
+    fn local_addr(&self) -> Option<SocketAddr>;
+}
+
+#[async_trait]
+impl<S: Server, F: ServerFactory<Server = S>> ManagedServer for Arc<VncServer<S, F>> {
+    async fn run(&self, listen: bool, ready: oneshot::Sender<()>) -> Result<(), ServerError> {
+        VncServer::run(self, listen, Some(ready)).await
+    }
+
+    fn serve(&self, stream: Box<dyn RfbStream>, addr: SocketAddr) -> Result<(), ServerError> {
+        VncServer::serve(self, stream, addr)
+    }
+
+    fn stop(&self) -> Result<(), ServerError> {
+        VncServer::stop(self)
+    }
+
+    async fn stop_gracefully(&self, timeout: Duration) -> Result<(), ServerError> {
+        VncServer::stop_gracefully(self, timeout).await
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        VncServer::local_addr(self)
+    }
+}
+
+struct Entry {
+    server: Arc<dyn ManagedServer>,
+    endpoint: Endpoint,
+
+    /// The task running the server, which finishes once the server has stopped.
+    task: JoinHandle<()>,
+}
+
+impl Entry {
+    /// Stop the server, giving its clients up to `drain` to finish, and wait for it to exit.
+    async fn stop(self, drain: Option<Duration>) {
+        let res = match drain {
+            Some(timeout) => self.server.stop_gracefully(timeout).await,
+            None => self.server.stop(),
+        };
+        // The server may have stopped on its own already.
+        if let Err(e) = res {
+            if !matches!(e, ServerError::NotRunning) {
+                warn!("could not stop server: {}", e);
+            }
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// Runs a set of [`VncServer`]s keyed by `K`.
+pub struct ServerManager<K> {
+    servers: Mutex<HashMap<K, Entry>>,
+
+    /// Set once [`ServerManager::shutdown`] has been called.
+    shutdown: watch::Sender<bool>,
+
+    /// Address the WebSocket listener is bound to, while it's running.
+    websocket_addr: Mutex<Option<SocketAddr>>,
+}
+
+impl<K> ServerManager<K>
+where
+    K: Clone + Eq + Hash + Debug + Send + Sync + 'static,
+{
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            servers: Mutex::new(HashMap::new()),
+            shutdown: watch::channel(false).0,
+            websocket_addr: Mutex::new(None),
+        })
+    }
+
+    /// Start `server` and manage it under `id`.
+    ///
+    /// The server must not already be running. Once this returns, the server is accepting
+    /// connections at `endpoint`.
+    pub async fn add<S, F>(
+        &self,
+        id: K,
+        server: Arc<VncServer<S, F>>,
+        endpoint: Endpoint,
+    ) -> Result<(), ManagerError>
+    where
+        S: Server,
+        F: ServerFactory<Server = S>,
+    {
+        if *self.shutdown.borrow() {
+            return Err(ManagerError::ShuttingDown);
+        }
+        self.check_available(&id, &endpoint)?;
+
+        let managed: Arc<dyn ManagedServer> = Arc::new(server);
+        let listen = endpoint == Endpoint::Listen;
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let runner = managed.clone();
+        let task_id = id.clone();
+        let task = tokio::spawn(async move {
+            let res = runner.run(listen, ready_tx).await;
+            if let Err(e) = &res {
+                error!("server {:?} exited: {}", task_id, e);
+            }
+            let _ = done_tx.send(res);
+        });
+
+        // The ready signal is dropped unsent if the server fails to start.
+        if ready_rx.await.is_err() {
+            return match done_rx.await {
+                Ok(Err(e)) => Err(e.into()),
+                _ => Err(ServerError::NotRunning.into()),
+            };
+        }
+
+        let mut servers = self.servers.lock().unwrap();
+        // Check again, since the lock was released while the server started.
+        if let Err(e) = Self::check_entries(&servers, &id, &endpoint) {
+            drop(servers);
+            let _ = managed.stop();
+            return Err(e);
+        }
+        info!("added server {:?} at {:?}", id, endpoint);
+        servers.insert(
+            id,
+            Entry {
+                server: managed,
+                endpoint,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop the server with the given id and stop managing it. If `drain` is set, its clients are
+    /// given that long to finish before they are disconnected.
+    pub async fn remove(&self, id: &K, drain: Option<Duration>) -> Result<(), ManagerError> {
+        let entry = self
+            .servers
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or(ManagerError::UnknownId)?;
+        info!("removing server {:?}", id);
+        entry.stop(drain).await;
+        Ok(())
+    }
+
+    /// Returns the ids of the managed servers.
+    pub fn ids(&self) -> Vec<K> {
+        self.servers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the address the server with the given id is listening on, if it has a listener of
+    /// its own.
+    pub fn local_addr(&self, id: &K) -> Option<SocketAddr> {
+        let servers = self.servers.lock().unwrap();
+        servers.get(id).and_then(|e| e.server.local_addr())
+    }
+
+    /// Returns the address of the WebSocket listener, if it's running.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        *self.websocket_addr.lock().unwrap()
+    }
+
+    /// Accept WebSocket connections on `addr`, handing each to the server whose
+    /// [`Endpoint::WebSocket`] path matches the request.
+    ///
+    /// This future runs until [`ServerManager::shutdown`] is called.
+    pub async fn listen_websocket(self: &Arc<Self>, addr: SocketAddr) -> Result<(), ManagerError> {
+        let mut shutdown = self.shutdown.subscribe();
+        if *shutdown.borrow() {
+            return Err(ManagerError::ShuttingDown);
+        }
+        let listener = TcpListener::bind(addr).await?;
+        {
+            let mut websocket_addr = self.websocket_addr.lock().unwrap();
+            if websocket_addr.is_some() {
+                return Err(ManagerError::AlreadyListening);
+            }
+            *websocket_addr = Some(listener.local_addr()?);
+        }
+        info!("WebSocket listener on {:?}", listener.local_addr()?);
+
+        loop {
+            let conn = select! {
+                _ = shutdown.wait_for(|s| *s) => break,
+                conn = listener.accept() => conn,
+            };
+            // Accept errors don't stop the listener, as the next accept may well succeed.
+            let (sock, peer) = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("could not accept WebSocket connection: {}", e);
+                    if !is_connection_error(&e) {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                    continue;
+                }
+            };
+            let manager = self.clone();
+            tokio::spawn(async move { manager.route_websocket(sock, peer).await });
+        }
+
+        *self.websocket_addr.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn route_websocket(&self, sock: TcpStream, peer: SocketAddr) {
+        let route = |path: &str| {
+            let servers = self.servers.lock().unwrap();
+            servers
+                .values()
+                .find(|e| matches!(&e.endpoint, Endpoint::WebSocket(p) if p == path))
+                .map(|e| e.server.clone())
+        };
+        let (ws, server) =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, websocket::accept(sock, route)).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    warn!(%peer, "WebSocket handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    warn!(%peer, "WebSocket handshake timed out");
+                    return;
+                }
+            };
+
+        let stream = websocket::bridge(ws);
+        if let Err(e) = server.serve(Box::new(stream), peer) {
+            warn!(%peer, "could not hand off WebSocket connection: {}", e);
+        }
+    }
+
+    /// Stop the WebSocket listener and every managed server. If `drain` is set, clients are given
+    /// that long to finish before they are disconnected.
+    pub async fn shutdown(&self, drain: Option<Duration>) {
+        self.shutdown.send_replace(true);
+        let entries: Vec<_> = self.servers.lock().unwrap().drain().collect();
+        info!("shutting down {} server(s)", entries.len());
+        future::join_all(entries.into_iter().map(|(_, e)| e.stop(drain))).await;
+    }
+
+    fn check_available(&self, id: &K, endpoint: &Endpoint) -> Result<(), ManagerError> {
+        Self::check_entries(&self.servers.lock().unwrap(), id, endpoint)
+    }
+
+    fn check_entries(
+        servers: &HashMap<K, Entry>,
+        id: &K,
+        endpoint: &Endpoint,
+    ) -> Result<(), ManagerError> {
+        if servers.contains_key(id) {
+            return Err(ManagerError::DuplicateId);
+        }
+        if let Endpoint::WebSocket(path) = endpoint {
+            if servers.values().any(|e| &e.endpoint == endpoint) {
+                return Err(ManagerError::DuplicatePath(path.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{Endpoint, ManagerError, ServerManager};
+    use crate::server::ServerState;
+    use crate::testing::test_server;
+
+    #[tokio::test]
+    async fn test_add_remove() {
+        let manager = ServerManager::new();
+        let first = test_server();
+        let second = test_server();
+        manager
+            .add("first", first.clone(), Endpoint::Listen)
+            .await
+            .unwrap();
+        manager
+            .add("second", second.clone(), Endpoint::Listen)
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager.add("first", test_server(), Endpoint::Listen).await,
+            Err(ManagerError::DuplicateId)
+        ));
+
+        let addr = manager.local_addr(&"second").unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut version = [0u8; 12];
+        client.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+
+        manager.remove(&"first", None).await.unwrap();
+        assert_eq!(first.state(), ServerState::Stopped);
+        assert_eq!(manager.ids(), vec!["second"]);
+
+        manager.shutdown(None).await;
+        assert_eq!(second.state(), ServerState::Stopped);
+        assert!(manager.ids().is_empty());
+        assert!(matches!(
+            manager.add("third", test_server(), Endpoint::Listen).await,
+            Err(ManagerError::ShuttingDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_routing() {
+        let manager = ServerManager::new();
+        manager
+            .add("vm", test_server(), Endpoint::WebSocket("/vm".to_string()))
+            .await
+            .unwrap();
+        let m = manager.clone();
+        let listener = tokio::spawn(async move {
+            m.listen_websocket(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap()
+        });
+        while manager.websocket_addr().is_none() {
+            tokio::task::yield_now().await;
+        }
+        let addr = manager.websocket_addr().unwrap();
+
+        let sock = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/vm", addr), sock)
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => assert_eq!(data, b"RFB 003.008\n"),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        let sock = TcpStream::connect(addr).await.unwrap();
+        assert!(
+            tokio_tungstenite::client_async(format!("ws://{}/other", addr), sock)
+                .await
+                .is_err()
+        );
+
+        manager.shutdown(None).await;
+        listener.await.unwrap();
+        assert!(manager.websocket_addr().is_none());
+    }
+}
@@ -0,0 +1,548 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Connection metrics
+//!
+//! Every connection counts the traffic it carries, the updates it sends and the input it
+//! receives. [`VncServer::metrics`](crate::server::VncServer::metrics) returns a snapshot of these
+//! counters along with server-wide totals, which [`ServerMetrics::to_prometheus`] renders in the
+//! Prometheus text format. With the `prometheus` feature, the server can also serve that text
+//! over HTTP itself.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clients::ClientId;
+use crate::encodings::EncodingType;
+
+/// The window over which a connection's frame rate is measured.
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A snapshot of a connection's metrics, or of totals across connections.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionMetrics {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub updates_sent: u64,
+
+    /// Rectangles sent, by encoding.
+    pub rects: HashMap<EncodingType, u64>,
+
+    /// Total time spent producing and encoding updates.
+    pub encode_time: Duration,
+
+    /// Total time between update requests arriving and the updates answering them being sent.
+    pub update_latency: Duration,
+
+    /// Updates sent per second, measured over the last second.
+    pub frame_rate: f64,
+
+    pub key_events: u64,
+    pub pointer_events: u64,
+}
+
+impl ConnectionMetrics {
+    /// The average time spent producing and encoding an update.
+    pub fn mean_encode_time(&self) -> Duration {
+        mean(self.encode_time, self.updates_sent)
+    }
+
+    /// The average time between an update being requested and sent.
+    pub fn mean_update_latency(&self) -> Duration {
+        mean(self.update_latency, self.updates_sent)
+    }
+
+    fn add(&mut self, other: &ConnectionMetrics) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.updates_sent += other.updates_sent;
+        for (encoding, n) in &other.rects {
+            *self.rects.entry(*encoding).or_default() += n;
+        }
+        self.encode_time += other.encode_time;
+        self.update_latency += other.update_latency;
+        self.frame_rate += other.frame_rate;
+        self.key_events += other.key_events;
+        self.pointer_events += other.pointer_events;
+    }
+}
+
+fn mean(total: Duration, n: u64) -> Duration {
+    match u32::try_from(n) {
+        Ok(0) => Duration::ZERO,
+        Ok(n) => total / n,
+        Err(_) => Duration::from_secs_f64(total.as_secs_f64() / n as f64),
+    }
+}
+
+/// A connection metric exported to Prometheus: its name, help text and how to read it.
+type Exported = (&'static str, &'static str, fn(&ConnectionMetrics) -> f64);
+
+const EXPORTED: [Exported; 8] = [
+    (
+        "bytes_received_total",
+        "Bytes received from clients.",
+        |m| m.bytes_in as f64,
+    ),
+    ("bytes_sent_total", "Bytes sent to clients.", |m| {
+        m.bytes_out as f64
+    }),
+    ("updates_sent_total", "Framebuffer updates sent.", |m| {
+        m.updates_sent as f64
+    }),
+    (
+        "encode_seconds_total",
+        "Time spent producing and encoding updates.",
+        |m| m.encode_time.as_secs_f64(),
+    ),
+    (
+        "update_latency_seconds_total",
+        "Time between update requests and the updates being sent.",
+        |m| m.update_latency.as_secs_f64(),
+    ),
+    ("key_events_total", "Key events received.", |m| {
+        m.key_events as f64
+    }),
+    ("pointer_events_total", "Pointer events received.", |m| {
+        m.pointer_events as f64
+    }),
+    (
+        "frame_rate",
+        "Updates sent per second over the last second.",
+        |m| m.frame_rate,
+    ),
+];
+
+/// A snapshot of the metrics of every connection to a server.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerMetrics {
+    /// Connections accepted since the server was created.
+    pub connections_accepted: u64,
+
+    /// Connections currently holding a connection slot.
+    pub connections_active: usize,
+
+    /// Totals across every connection the server has had, open or closed. The frame rate is the
+    /// sum over open connections.
+    pub totals: ConnectionMetrics,
+
+    /// The metrics of each open connection, including any queued for a connection slot.
+    pub connections: BTreeMap<ClientId, ConnectionMetrics>,
+}
+
+impl ServerMetrics {
+    /// Render the metrics in the Prometheus text exposition format.
+    ///
+    /// Server-wide totals are reported as `rfb_*` metrics, and each open connection's metrics as
+    /// `rfb_connection_*` metrics labelled with its client id.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        family(
+            &mut out,
+            "rfb_connections_accepted_total",
+            "counter",
+            "Connections accepted since the server was created.",
+            [(String::new(), self.connections_accepted as f64)],
+        );
+        family(
+            &mut out,
+            "rfb_connections_active",
+            "gauge",
+            "Connections currently holding a connection slot.",
+            [(String::new(), self.connections_active as f64)],
+        );
+
+        let totals = [(String::new(), &self.totals)];
+        let conns = self
+            .connections
+            .iter()
+            .map(|(id, m)| (format!("client=\"{}\"", id), m))
+            .collect::<Vec<_>>();
+        for (prefix, series) in [("rfb", &totals[..]), ("rfb_connection", &conns[..])] {
+            for (name, help, value) in EXPORTED {
+                let kind = match name.ends_with("_total") {
+                    true => "counter",
+                    false => "gauge",
+                };
+                family(
+                    &mut out,
+                    &format!("{}_{}", prefix, name),
+                    kind,
+                    help,
+                    series.iter().map(|(labels, m)| (labels.clone(), value(m))),
+                );
+            }
+
+            let mut rects = Vec::new();
+            for (labels, m) in series {
+                let mut by_encoding = m.rects.iter().collect::<Vec<_>>();
+                by_encoding.sort_by_key(|(encoding, _)| i32::from(**encoding));
+                for (encoding, n) in by_encoding {
+                    let encoding = format!("encoding=\"{:?}\"", encoding);
+                    let labels = match labels.is_empty() {
+                        true => encoding,
+                        false => format!("{},{}", labels, encoding),
+                    };
+                    rects.push((labels, *n as f64));
+                }
+            }
+            family(
+                &mut out,
+                &format!("{}_rectangles_sent_total", prefix),
+                "counter",
+                "Rectangles sent, by encoding.",
+                rects,
+            );
+        }
+        out
+    }
+}
+
+/// Write one metric family with its samples.
+fn family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        match labels.is_empty() {
+            true => {
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+            false => {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+}
+
+/// The live counters behind a connection's [`ConnectionMetrics`].
+#[derive(Default)]
+pub(crate) struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    updates_sent: AtomicU64,
+    encode_nanos: AtomicU64,
+    latency_nanos: AtomicU64,
+    key_events: AtomicU64,
+    pointer_events: AtomicU64,
+    rects: Mutex<HashMap<EncodingType, u64>>,
+
+    /// When each update in the last [`FRAME_RATE_WINDOW`] was sent.
+    recent_updates: Mutex<VecDeque<Instant>>,
+}
+
+impl Counters {
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn record_read(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Record an update with rectangles in `encodings` being sent, having taken `encode_time` to
+    /// produce and `latency` since it was requested.
+    pub fn record_update(
+        &self,
+        encodings: impl IntoIterator<Item = EncodingType>,
+        encode_time: Duration,
+        latency: Duration,
+    ) {
+        self.updates_sent.fetch_add(1, Ordering::Relaxed);
+        self.encode_nanos
+            .fetch_add(encode_time.as_nanos() as u64, Ordering::Relaxed);
+        self.latency_nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+
+        let mut rects = self.rects.lock().unwrap();
+        for encoding in encodings {
+            *rects.entry(encoding).or_default() += 1;
+        }
+        drop(rects);
+
+        let now = Instant::now();
+        let mut recent = self.recent_updates.lock().unwrap();
+        recent.push_back(now);
+        prune(&mut recent, now);
+    }
+
+    pub fn record_key_event(&self) {
+        self.key_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_pointer_event(&self) {
+        self.pointer_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ConnectionMetrics {
+        let frame_rate = {
+            let mut recent = self.recent_updates.lock().unwrap();
+            prune(&mut recent, Instant::now());
+            recent.len() as f64 / FRAME_RATE_WINDOW.as_secs_f64()
+        };
+        ConnectionMetrics {
+            bytes_in: self.bytes_in(),
+            bytes_out: self.bytes_out(),
+            updates_sent: self.updates_sent.load(Ordering::Relaxed),
+            rects: self.rects.lock().unwrap().clone(),
+            encode_time: Duration::from_nanos(self.encode_nanos.load(Ordering::Relaxed)),
+            update_latency: Duration::from_nanos(self.latency_nanos.load(Ordering::Relaxed)),
+            frame_rate,
+            key_events: self.key_events.load(Ordering::Relaxed),
+            pointer_events: self.pointer_events.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Drop updates that have fallen out of the frame rate window.
+fn prune(recent: &mut VecDeque<Instant>, now: Instant) {
+    while let Some(&t) = recent.front() {
+        if now.duration_since(t) < FRAME_RATE_WINDOW {
+            break;
+        }
+        recent.pop_front();
+    }
+}
+
+/// Server-wide totals, folding in connections as they close.
+#[derive(Default)]
+pub(crate) struct Totals {
+    closed: Mutex<ConnectionMetrics>,
+}
+
+impl Totals {
+    pub fn record_closed(&self, counters: &Counters) {
+        let mut final_metrics = counters.snapshot();
+        final_metrics.frame_rate = 0.0;
+        self.closed.lock().unwrap().add(&final_metrics);
+    }
+
+    /// Combine the totals from closed connections with the metrics of those still open.
+    pub fn snapshot(
+        &self,
+        connections_accepted: u64,
+        connections_active: usize,
+        connections: BTreeMap<ClientId, ConnectionMetrics>,
+    ) -> ServerMetrics {
+        let mut totals = self.closed.lock().unwrap().clone();
+        for m in connections.values() {
+            totals.add(m);
+        }
+        ServerMetrics {
+            connections_accepted,
+            connections_active,
+            totals,
+            connections,
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod http {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tracing::{debug, info, warn};
+
+    use crate::rfb::RfbStream;
+    use crate::server::{
+        is_connection_error, Server, ServerError, ServerFactory, VncServer, ACCEPT_BACKOFF,
+    };
+
+    /// The most we read of a request before giving up on it.
+    const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+    /// How long a client has to send its request and read the response.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    impl<S: Server, F: ServerFactory<Server = S>> VncServer<S, F> {
+        /// Serve the server's metrics as Prometheus text over HTTP on `addr`, at `/metrics`.
+        ///
+        /// This future runs until it is dropped, independent of whether the server itself is
+        /// running.
+        pub async fn serve_metrics(self: &Arc<Self>, addr: SocketAddr) -> Result<(), ServerError> {
+            let listener = TcpListener::bind(addr).await?;
+            info!(
+                "serving metrics on http://{}/metrics",
+                listener.local_addr()?
+            );
+            loop {
+                let (sock, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("could not accept metrics connection: {}", e);
+                        if !is_connection_error(&e) {
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        }
+                        continue;
+                    }
+                };
+                let server = self.clone();
+                tokio::spawn(async move {
+                    let render = || server.metrics().to_prometheus();
+                    let res = respond(sock, REQUEST_TIMEOUT, render).await;
+                    if let Err(e) = res {
+                        debug!(%peer, "could not answer metrics request: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Answer one HTTP request on `stream`, with the output of `render` if it was for
+    /// `/metrics`, giving up if that takes longer than `timeout`.
+    pub(super) async fn respond(
+        stream: impl RfbStream,
+        timeout: Duration,
+        render: impl FnOnce() -> String,
+    ) -> io::Result<()> {
+        tokio::time::timeout(timeout, answer(stream, render))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+    }
+
+    async fn answer(mut stream: impl RfbStream, render: impl FnOnce() -> String) -> io::Result<()> {
+        let mut req = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || req.len() + n > MAX_REQUEST_LEN {
+                return Ok(());
+            }
+            req.extend_from_slice(&buf[..n]);
+        }
+
+        let request_line = req.split(|&b| b == b'\r').next().unwrap_or_default();
+        let mut parts = request_line.split(|&b| b == b' ');
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some(b"GET"), Some(b"/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        };
+        let resp = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(resp.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::{Counters, Totals};
+    use crate::clients::{ClientId, Connections};
+    use crate::encodings::EncodingType;
+    use crate::listener::PeerAddr;
+
+    #[test]
+    fn test_totals_include_closed() {
+        let closed = Counters::default();
+        closed.record_read(10);
+        closed.record_update(
+            [EncodingType::Raw],
+            Duration::from_millis(4),
+            Duration::ZERO,
+        );
+        let totals = Totals::default();
+        totals.record_closed(&closed);
+
+        let open = Counters::default();
+        open.record_write(5);
+        open.record_key_event();
+        open.record_update(
+            [EncodingType::Raw, EncodingType::CopyRect],
+            Duration::from_millis(2),
+            Duration::from_millis(6),
+        );
+        let snapshot = open.snapshot();
+        assert_eq!(snapshot.frame_rate, 1.0);
+
+        let conns = BTreeMap::from([(ClientId(1), snapshot)]);
+        let metrics = totals.snapshot(2, 1, conns);
+        assert_eq!(metrics.totals.bytes_in, 10);
+        assert_eq!(metrics.totals.bytes_out, 5);
+        assert_eq!(metrics.totals.updates_sent, 2);
+        assert_eq!(metrics.totals.rects[&EncodingType::Raw], 2);
+        assert_eq!(metrics.totals.rects[&EncodingType::CopyRect], 1);
+        assert_eq!(metrics.totals.mean_encode_time(), Duration::from_millis(3));
+        assert_eq!(metrics.totals.frame_rate, 1.0);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("rfb_connections_accepted_total 2\n"));
+        assert!(text.contains("rfb_updates_sent_total 2\n"));
+        assert!(text.contains("rfb_rectangles_sent_total{encoding=\"CopyRect\"} 1\n"));
+        assert!(text.contains("rfb_connection_key_events_total{client=\"1\"} 1\n"));
+        assert!(text.contains("# TYPE rfb_connection_frame_rate gauge\n"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_http_endpoint() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let timeout = Duration::from_secs(10);
+        let render = || "rfb_up 1\n".to_string();
+        let task = tokio::spawn(super::http::respond(server, timeout, render));
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("\r\n\r\nrfb_up 1\n"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_http_timeout() {
+        // A client that never finishes its request is given up on.
+        let (_client, server) = tokio::io::duplex(4096);
+        let render = || unreachable!();
+        let res = super::http::respond(server, Duration::from_millis(50), render).await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_cleared_connections_count_towards_totals() {
+        let conns = Connections::default();
+        let (client, _channels) = conns.register(PeerAddr::Unix(None), None);
+        client.metrics.record_read(10);
+
+        conns.clear();
+        let metrics = conns.metrics();
+        assert!(metrics.connections.is_empty());
+        assert_eq!(metrics.totals.bytes_in, 10);
+    }
+}
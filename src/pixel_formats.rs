@@ -78,6 +78,7 @@ pub mod fourcc {
     pub const FOURCC_BX24: u32 = 0x34325842; // little-endian BGRx, 8:8:8:8
     pub const FOURCC_XB24: u32 = 0x34324258; // little-endian xBGR, 8:8:8:8
 
+    #[allow(clippy::identity_op, clippy::erasing_op)]
     pub fn fourcc_to_pixel_format(fourcc: u32) -> Result<PixelFormat, PixelFormatError> {
         match fourcc {
             // little-endian xRGB
@@ -202,7 +203,7 @@ pub mod rgb_888 {
     }
 
     /// Translate between RGB888 formats. The input and output format must both be RGB888.
-    pub fn transform(pixels: &Vec<u8>, input: &PixelFormat, output: &PixelFormat) -> Vec<u8> {
+    pub fn transform(pixels: &[u8], input: &PixelFormat, output: &PixelFormat) -> Vec<u8> {
         assert!(input.is_rgb_888());
         assert!(output.is_rgb_888());
 
@@ -0,0 +1,678 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Proxying viewers to an upstream RFB server
+//!
+//! An [`RfbProxy`] is a [`Server`] backend that connects to another RFB server (QEMU, say) as a
+//! client. Viewers connect to a [`VncServer`] as usual and authenticate against its own security
+//! settings. The proxy keeps a copy of the upstream framebuffer, which viewers are sent updates
+//! from in whatever format they ask for as soon as it changes, and relays viewer input upstream,
+//! optionally through an [`InputFilter`]. Clipboard text is relayed both ways, and bells from
+//! upstream are passed on to every viewer.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::clients::{ClientContext, ClientHandle};
+use crate::damage::DamageNotifier;
+use crate::encodings::EncodingType;
+use crate::framebuffer::{Framebuffer, FramebufferError};
+use crate::pixel_formats::fourcc;
+use crate::region::Rect;
+use crate::rfb::{
+    read_latin1, read_string, ClientInit, ClientMessage, FramebufferUpdate,
+    FramebufferUpdateRequest, KeyEvent, PointerEvent, ProtoVersion, ProtocolError, ReadMessage,
+    ReadStream, RfbStream, SecurityResult, SecurityType, SecurityTypes, ServerInit, WriteMessage,
+};
+use crate::server::{BackendError, Server, ServerError, ServerFactory, VncServer, VncServerData};
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("upstream server offers no supported security type")]
+    UnsupportedSecurity,
+
+    #[error("upstream authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("upstream sent an unsupported encoding ({0:?})")]
+    UnsupportedEncoding(EncodingType),
+
+    #[error("upstream sent a rectangle outside the framebuffer")]
+    InvalidRectangle,
+
+    #[error(transparent)]
+    Framebuffer(#[from] FramebufferError),
+
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Decides which viewer input is passed upstream.
+pub trait InputFilter: Send + Sync + 'static {
+    /// Returns whether `ke` should be passed upstream.
+    fn key_event(&self, _ctx: &ClientContext, _ke: &KeyEvent) -> bool {
+        true
+    }
+
+    /// Returns whether `pe` should be passed upstream.
+    fn pointer_event(&self, _ctx: &ClientContext, _pe: &PointerEvent) -> bool {
+        true
+    }
+
+    /// Returns whether clipboard `text` should be passed upstream.
+    fn cut_text(&self, _ctx: &ClientContext, _text: &str) -> bool {
+        true
+    }
+}
+
+/// Lists the viewers of the server a proxy is attached to.
+type ViewerList = Box<dyn Fn() -> Vec<ClientHandle> + Send + Sync>;
+
+/// State shared between the proxy and the task reading from the upstream server.
+struct Upstream {
+    /// The upstream framebuffer, in the pixel format the proxy asked for.
+    framebuffer: StdMutex<Framebuffer>,
+    writer: Mutex<WriteHalf<Box<dyn RfbStream>>>,
+
+    /// Lists the viewers that bells and clipboard text from upstream are passed on to.
+    viewers: StdMutex<Option<ViewerList>>,
+
+    /// Why the upstream connection closed, once it has.
+    closed: StdMutex<Option<String>>,
+}
+
+impl Upstream {
+    async fn send(&self, msg: ClientMessage) -> Result<(), ProtocolError> {
+        let mut writer = self.writer.lock().await;
+        msg.write_to(&mut *writer).await
+    }
+
+    async fn request_update(&self, incremental: bool) -> Result<(), ProtocolError> {
+        let (width, height) = {
+            let fb = self.framebuffer.lock().unwrap();
+            (fb.width(), fb.height())
+        };
+        let req = FramebufferUpdateRequest::new(incremental, 0, 0, width, height);
+        self.send(ClientMessage::FramebufferUpdateRequest(req))
+            .await
+    }
+
+    /// Run `f` on every viewer, if the proxy has been attached to a server.
+    fn for_each_viewer(&self, f: impl Fn(&ClientHandle) -> Result<(), ServerError>) {
+        let viewers = match &*self.viewers.lock().unwrap() {
+            Some(viewers) => viewers(),
+            None => return,
+        };
+        for viewer in viewers {
+            // The viewer may have disconnected since it was listed.
+            let _ = f(&viewer);
+        }
+    }
+
+    /// Read and apply the next message from the upstream server.
+    async fn handle_message(&self, rd: &mut dyn ReadStream) -> Result<(), ProxyError> {
+        match rd.read_u8().await? {
+            0 => {
+                // FramebufferUpdate: 1 byte of padding, then the rectangles
+                rd.read_u8().await?;
+                let n_rects = rd.read_u16().await?;
+                for _ in 0..n_rects {
+                    self.read_rectangle(rd).await?;
+                }
+                // Viewers already heard of the changes through the damage notifier.
+                self.framebuffer.lock().unwrap().take_damage();
+                self.request_update(true).await?;
+            }
+            1 => {
+                // SetColorMapEntries: the proxy asked for a true colour pixel format, so there is
+                // no colour map to keep.
+                rd.read_u8().await?;
+                let _first_color = rd.read_u16().await?;
+                let n_colors = rd.read_u16().await?;
+                let mut buf = vec![0u8; n_colors as usize * 6];
+                rd.read_exact(&mut buf).await?;
+            }
+            2 => {
+                debug!("upstream: Bell");
+                self.for_each_viewer(|v| v.bell());
+            }
+            3 => {
+                // ServerCutText: 3 bytes of padding, then the text
+                let mut padding = [0u8; 3];
+                rd.read_exact(&mut padding).await?;
+                let text = read_latin1(rd).await?;
+                debug!(
+                    "upstream: ServerCutText ({} characters)",
+                    text.chars().count()
+                );
+                self.for_each_viewer(|v| v.cut_text(text.clone()));
+            }
+            t => return Err(ProtocolError::UnknownServerMessageType(t).into()),
+        }
+
+        Ok(())
+    }
+
+    async fn read_rectangle(&self, rd: &mut dyn ReadStream) -> Result<(), ProxyError> {
+        let x = rd.read_u16().await?;
+        let y = rd.read_u16().await?;
+        let w = rd.read_u16().await?;
+        let h = rd.read_u16().await?;
+        let encoding = EncodingType::from(rd.read_i32().await?);
+
+        // Check the rectangle before reading any more, so its size is known to be sensible.
+        let rect = Rect::new(x, y, w, h);
+        let (bounds, bytes_per_pixel) = {
+            let fb = self.framebuffer.lock().unwrap();
+            (fb.bounds(), fb.bytes_per_pixel())
+        };
+        if !bounds.contains(&rect) {
+            return Err(ProxyError::InvalidRectangle);
+        }
+
+        match encoding {
+            EncodingType::Raw => {
+                let stride = w as usize * bytes_per_pixel;
+                let mut data = vec![0u8; stride * h as usize];
+                rd.read_exact(&mut data).await?;
+                self.framebuffer
+                    .lock()
+                    .unwrap()
+                    .blit(x, y, w, &data, stride)?;
+            }
+            EncodingType::CopyRect => {
+                let src = Rect::new(rd.read_u16().await?, rd.read_u16().await?, w, h);
+                if !bounds.contains(&src) {
+                    return Err(ProxyError::InvalidRectangle);
+                }
+                self.framebuffer.lock().unwrap().copy_rect(src, x, y);
+            }
+            e => return Err(ProxyError::UnsupportedEncoding(e)),
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`Server`] backend that relays to an upstream RFB server.
+pub struct RfbProxy {
+    upstream: Arc<Upstream>,
+    name: String,
+    filter: Option<Box<dyn InputFilter>>,
+
+    /// Tells viewers waiting for an update that the upstream framebuffer changed.
+    notifier: DamageNotifier,
+
+    /// Reads messages from the upstream server until the connection closes.
+    reader: JoinHandle<()>,
+}
+
+impl RfbProxy {
+    /// Connect to the RFB server at `addr`.
+    pub async fn connect(addr: SocketAddr) -> Result<Self, ProxyError> {
+        let stream = TcpStream::connect(addr).await?;
+        info!("connected to upstream server at {:?}", addr);
+        Self::from_stream(stream).await
+    }
+
+    /// Complete the client side of the RFB handshake on `stream`, which must be connected to an
+    /// RFB server.
+    ///
+    /// Only upstream servers that allow the `None` security type are supported.
+    pub async fn from_stream(stream: impl RfbStream + 'static) -> Result<Self, ProxyError> {
+        let mut stream: Box<dyn RfbStream> = Box::new(stream);
+        let server_init = handshake(&mut stream).await?;
+        info!("upstream ServerInit={:?}", server_init);
+
+        // Whatever the upstream format, ask for 32-bit true colour, which viewers can be sent
+        // updates from in any format they ask for.
+        let pixel_format = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        ClientMessage::SetPixelFormat(pixel_format.clone())
+            .write_to(&mut stream)
+            .await?;
+
+        let mut framebuffer =
+            Framebuffer::new(server_init.width(), server_init.height(), pixel_format);
+        let notifier = DamageNotifier::new();
+        framebuffer.set_damage_notifier(notifier.clone());
+        let (mut rd, wr) = tokio::io::split(stream);
+        let upstream = Arc::new(Upstream {
+            framebuffer: StdMutex::new(framebuffer),
+            writer: Mutex::new(wr),
+            viewers: StdMutex::new(None),
+            closed: StdMutex::new(None),
+        });
+
+        // Only encodings that can be applied to the framebuffer directly are asked for.
+        upstream
+            .send(ClientMessage::SetEncodings(vec![
+                EncodingType::Raw,
+                EncodingType::CopyRect,
+            ]))
+            .await?;
+        upstream.request_update(false).await?;
+
+        let reader_upstream = upstream.clone();
+        let reader = tokio::spawn(async move {
+            let err = loop {
+                if let Err(e) = reader_upstream.handle_message(&mut rd).await {
+                    break e;
+                }
+            };
+            warn!("upstream connection closed: {}", err);
+            *reader_upstream.closed.lock().unwrap() = Some(err.to_string());
+        });
+
+        Ok(Self {
+            upstream,
+            name: server_init.name().to_string(),
+            filter: None,
+            notifier,
+            reader,
+        })
+    }
+
+    /// Pass viewer input through `filter` before relaying it upstream.
+    pub fn with_filter(mut self, filter: impl InputFilter) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Pass bells and clipboard text from the upstream server on to the viewers of `server`,
+    /// which should be the server this proxy is the backend of.
+    pub fn attach<F: ServerFactory<Server = Self>>(&self, server: &Arc<VncServer<Self, F>>) {
+        let server: Weak<VncServer<Self, F>> = Arc::downgrade(server);
+        *self.upstream.viewers.lock().unwrap() = Some(Box::new(move || {
+            server.upgrade().map(|s| s.clients()).unwrap_or_default()
+        }));
+    }
+
+    /// The desktop name reported by the upstream server.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the server data to create the viewer-facing [`VncServer`] with, matching the
+    /// upstream framebuffer.
+    pub fn server_data(&self) -> VncServerData {
+        let fb = self.upstream.framebuffer.lock().unwrap();
+        VncServerData {
+            width: fb.width(),
+            height: fb.height(),
+            input_pixel_format: fb.pixel_format().clone(),
+        }
+    }
+
+    fn check_open(&self) -> Result<(), BackendError> {
+        match &*self.upstream.closed.lock().unwrap() {
+            Some(reason) => Err(BackendError::Fatal(format!(
+                "upstream connection closed: {}",
+                reason
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    async fn relay(&self, msg: ClientMessage) -> Result<(), BackendError> {
+        self.check_open()?;
+        self.upstream
+            .send(msg)
+            .await
+            .map_err(|e| BackendError::Fatal(format!("could not relay input upstream: {}", e)))
+    }
+}
+
+impl Drop for RfbProxy {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Run the client side of the RFB handshake, up to and including ServerInit.
+async fn handshake(s: &mut Box<dyn RfbStream>) -> Result<ServerInit, ProxyError> {
+    let version = ProtoVersion::read_from(s).await?;
+    debug!("upstream ProtoVersion={:?}", version);
+    version.write_to(s).await?;
+
+    match version {
+        ProtoVersion::Rfb33 => {
+            // The server picks the security type.
+            match s.read_u32().await? {
+                0 => return Err(ProtocolError::ConnectionRefused(read_string(s).await?).into()),
+                1 => {}
+                _ => return Err(ProxyError::UnsupportedSecurity),
+            }
+        }
+        ProtoVersion::Rfb37 | ProtoVersion::Rfb38 => {
+            let offered = SecurityTypes::read_from(s).await?;
+            debug!("upstream SecurityTypes={:?}", offered);
+            if !offered.0.contains(&SecurityType::None) {
+                return Err(ProxyError::UnsupportedSecurity);
+            }
+            SecurityType::None.write_to(s).await?;
+        }
+    }
+
+    // Before 3.8, there is no security result for the None security type.
+    if version == ProtoVersion::Rfb38 {
+        if let SecurityResult::Failure(reason) = SecurityResult::read_from(s).await? {
+            return Err(ProxyError::AuthenticationFailed(reason));
+        }
+    }
+
+    // Other viewers of the upstream server are left connected.
+    ClientInit { shared: true }.write_to(s).await?;
+    Ok(ServerInit::read_from(s).await?)
+}
+
+#[async_trait]
+impl Server for RfbProxy {
+    async fn get_framebuffer_update(
+        &self,
+        _ctx: &ClientContext,
+        area: Rect,
+    ) -> Result<FramebufferUpdate, BackendError> {
+        self.check_open()?;
+        Ok(self.upstream.framebuffer.lock().unwrap().update(area))
+    }
+
+    fn damage_notifier(&self) -> Option<DamageNotifier> {
+        Some(self.notifier.clone())
+    }
+
+    async fn key_event(&self, ctx: &ClientContext, ke: KeyEvent) -> Result<(), BackendError> {
+        if let Some(filter) = &self.filter {
+            if !filter.key_event(ctx, &ke) {
+                debug!("filtered key event: {:?}", ke);
+                return Ok(());
+            }
+        }
+        self.relay(ClientMessage::KeyEvent(ke)).await
+    }
+
+    async fn pointer_event(
+        &self,
+        ctx: &ClientContext,
+        pe: PointerEvent,
+    ) -> Result<(), BackendError> {
+        if let Some(filter) = &self.filter {
+            if !filter.pointer_event(ctx, &pe) {
+                debug!("filtered pointer event: {:?}", pe);
+                return Ok(());
+            }
+        }
+        self.relay(ClientMessage::PointerEvent(pe)).await
+    }
+
+    async fn cut_text(&self, ctx: &ClientContext, text: String) -> Result<(), BackendError> {
+        if let Some(filter) = &self.filter {
+            if !filter.cut_text(ctx, &text) {
+                debug!("filtered cut text ({} characters)", text.chars().count());
+                return Ok(());
+            }
+        }
+        self.relay(ClientMessage::ClientCutText(text)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{InputFilter, RfbProxy};
+    use crate::clients::ClientContext;
+    use crate::encodings::RawEncoding;
+    use crate::pixel_formats::fourcc;
+    use crate::region::Rect;
+    use crate::rfb::{FramebufferUpdate, KeyEvent, Rectangle, ServerInit, WriteMessage};
+    use crate::server::{BackendError, Server, VncServer, VncServerConfig, VncServerData};
+    use crate::testing::{connect, run, test_config};
+
+    const SIZE: u16 = 4;
+
+    /// An upstream display filled with a fixed pattern, which records the keys and clipboard
+    /// text it receives.
+    #[derive(Default)]
+    struct Display {
+        pixels: Mutex<Vec<u8>>,
+        keys: Mutex<Vec<u32>>,
+        cut_text: Mutex<Vec<String>>,
+    }
+
+    fn pattern() -> Vec<u8> {
+        (0..SIZE as usize * SIZE as usize * 4)
+            .map(|i| i as u8)
+            .collect()
+    }
+
+    #[async_trait]
+    impl Server for Display {
+        async fn get_framebuffer_update(
+            &self,
+            _ctx: &ClientContext,
+            _area: Rect,
+        ) -> Result<FramebufferUpdate, BackendError> {
+            let pixels = self.pixels.lock().unwrap().clone();
+            let r = Rectangle::new(0, 0, SIZE, SIZE, Box::new(RawEncoding::new(pixels)));
+            Ok(FramebufferUpdate::new(vec![r]))
+        }
+
+        async fn key_event(&self, _ctx: &ClientContext, ke: KeyEvent) -> Result<(), BackendError> {
+            self.keys.lock().unwrap().push(ke.keysym_raw());
+            Ok(())
+        }
+
+        async fn cut_text(&self, _ctx: &ClientContext, text: String) -> Result<(), BackendError> {
+            self.cut_text.lock().unwrap().push(text);
+            Ok(())
+        }
+    }
+
+    /// Blocks the 'q' key, and pasting it.
+    struct NoQuit;
+
+    impl InputFilter for NoQuit {
+        fn key_event(&self, _ctx: &ClientContext, ke: &KeyEvent) -> bool {
+            ke.keysym_raw() != 'q' as u32
+        }
+
+        fn cut_text(&self, _ctx: &ClientContext, text: &str) -> bool {
+            text != "q"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let data = VncServerData {
+            width: SIZE,
+            height: SIZE,
+            input_pixel_format: fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap(),
+        };
+        let display = Display {
+            pixels: Mutex::new(pattern()),
+            ..Default::default()
+        };
+        let upstream = VncServer::new(display, test_config(), data);
+        run(&upstream).await;
+
+        let proxy = RfbProxy::connect(upstream.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_filter(NoQuit);
+        assert_eq!(proxy.name(), "test");
+        let data = proxy.server_data();
+        // Viewers aren't polled for, so only hear of upstream changes through the proxy's
+        // damage notifier.
+        let config = VncServerConfig {
+            update_poll_interval: None,
+            ..test_config()
+        };
+        let server = VncServer::new(proxy, config, data);
+        server.factory.server().attach(&server);
+        run(&server).await;
+        let mut viewer = connect(&server).await;
+
+        // Ask for updates until the proxy has received the upstream framebuffer.
+        let pixels = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                viewer
+                    .write_all(&[3, 0, 0, 0, 0, 0, 0, SIZE as u8, 0, SIZE as u8])
+                    .await
+                    .unwrap();
+                let mut header = [0u8; 16];
+                viewer.read_exact(&mut header).await.unwrap();
+                let mut pixels = vec![0u8; SIZE as usize * SIZE as usize * 4];
+                viewer.read_exact(&mut pixels).await.unwrap();
+                if pixels == pattern() {
+                    break pixels;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(pixels, pattern());
+
+        // A change upstream answers the viewer's incremental request with just that change.
+        viewer
+            .write_all(&[3, 1, 0, 0, 0, 0, 0, SIZE as u8, 0, SIZE as u8])
+            .await
+            .unwrap();
+        upstream.factory.server().pixels.lock().unwrap()[0] = 0xff;
+        let mut update = [0u8; 4 + 12 + 4];
+        tokio::time::timeout(Duration::from_secs(5), viewer.read_exact(&mut update))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&update[..12], &[0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(update[16], 0xff);
+
+        // KeyEvent: press 'q', which is filtered, then 'a'
+        viewer
+            .write_all(&[4, 1, 0, 0, 0, 0, 0, b'q'])
+            .await
+            .unwrap();
+        viewer
+            .write_all(&[4, 1, 0, 0, 0, 0, 0, b'a'])
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while upstream.factory.server().keys.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            *upstream.factory.server().keys.lock().unwrap(),
+            vec!['a' as u32]
+        );
+
+        // Clipboard text is relayed both ways, through the filter on its way upstream, and bells
+        // from upstream reach the viewer.
+        viewer
+            .write_all(&[6, 0, 0, 0, 0, 0, 0, 1, b'q'])
+            .await
+            .unwrap();
+        viewer
+            .write_all(&[6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i'])
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while upstream
+                .factory
+                .server()
+                .cut_text
+                .lock()
+                .unwrap()
+                .is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            *upstream.factory.server().cut_text.lock().unwrap(),
+            vec!["hi".to_string()]
+        );
+
+        let upstream_client = &upstream.clients()[0];
+        upstream_client.cut_text("yo".to_string()).unwrap();
+        upstream_client.bell().unwrap();
+        let mut msgs = [0u8; 11];
+        viewer.read_exact(&mut msgs).await.unwrap();
+        assert_eq!(msgs, [3, 0, 0, 0, 0, 0, 0, 2, b'y', b'o', 2]);
+
+        server.stop().unwrap();
+        upstream.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_rectangle() {
+        let (proxy_end, mut upstream) = tokio::io::duplex(1 << 16);
+        let handshake = async {
+            let mut version = [0u8; 12];
+            upstream.write_all(b"RFB 003.008\n").await.unwrap();
+            upstream.read_exact(&mut version).await.unwrap();
+            // One security type, None, which succeeds.
+            upstream.write_all(&[1, 1]).await.unwrap();
+            assert_eq!(upstream.read_u8().await.unwrap(), 1);
+            upstream.write_u32(0).await.unwrap();
+            assert_eq!(upstream.read_u8().await.unwrap(), 1);
+            let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_BX24).unwrap();
+            ServerInit::new(SIZE, SIZE, "upstream".to_string(), pf)
+                .write_to(&mut upstream)
+                .await
+                .unwrap();
+        };
+        let (proxy, ()) = tokio::join!(RfbProxy::from_stream(proxy_end), handshake);
+        let proxy = proxy.unwrap();
+
+        // The proxy asks for its own pixel format before anything else.
+        let mut set_pixel_format = [0u8; 20];
+        upstream.read_exact(&mut set_pixel_format).await.unwrap();
+        assert_eq!(set_pixel_format[0], 0);
+        assert_eq!(
+            proxy.server_data().input_pixel_format,
+            fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap()
+        );
+
+        // A Raw rectangle far larger than the framebuffer closes the connection, without the
+        // proxy trying to read it in.
+        upstream
+            .write_all(&[0, 0, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while proxy.check_open().is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let Err(BackendError::Fatal(e)) = proxy.check_open() else {
+            panic!("upstream connection should be closed");
+        };
+        assert!(e.contains("outside the framebuffer"), "{}", e);
+    }
+}
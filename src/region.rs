@@ -0,0 +1,308 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Rectangles and regions of the framebuffer
+//!
+//! A [`Region`] is a set of non-overlapping [`Rect`]s, used to track which parts of the
+//! framebuffer have changed since a client was last sent an update.
+
+use std::cmp::{max, min};
+
+/// Past this many rectangles, a region is simplified to its bounding box rather than sending
+/// a client many tiny rectangles.
+const MAX_RECTS: usize = 64;
+
+/// A rectangle of the framebuffer.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The x coordinate one past the right edge.
+    pub fn right(&self) -> u32 {
+        self.x as u32 + self.width as u32
+    }
+
+    /// The y coordinate one past the bottom edge.
+    pub fn bottom(&self) -> u32 {
+        self.y as u32 + self.height as u32
+    }
+
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns true if `other` lies entirely within this rectangle.
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.is_empty()
+            || (other.x >= self.x
+                && other.y >= self.y
+                && other.right() <= self.right()
+                && other.bottom() <= self.bottom())
+    }
+
+    /// The part of this rectangle that also lies in `other`, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = max(self.x, other.x);
+        let y = max(self.y, other.y);
+        let right = min(self.right(), other.right());
+        let bottom = min(self.bottom(), other.bottom());
+        if right <= x as u32 || bottom <= y as u32 {
+            return None;
+        }
+        Some(Rect::new(
+            x,
+            y,
+            (right - x as u32) as u16,
+            (bottom - y as u32) as u16,
+        ))
+    }
+
+    /// The smallest rectangle containing both this rectangle and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = min(self.x, other.x);
+        let y = min(self.y, other.y);
+        let right = max(self.right(), other.right());
+        let bottom = max(self.bottom(), other.bottom());
+        Rect::new(
+            x,
+            y,
+            saturate(right - x as u32),
+            saturate(bottom - y as u32),
+        )
+    }
+
+    /// The parts of this rectangle outside of `other`, as up to four non-overlapping rectangles.
+    pub fn subtract(&self, other: &Rect) -> Vec<Rect> {
+        let Some(overlap) = self.intersection(other) else {
+            return vec![*self];
+        };
+        let mut parts = Vec::with_capacity(4);
+        // Bands above and below the overlap span the full width. Parts that start past the last
+        // coordinate a rectangle can have are left out, as no framebuffer reaches them...
+        if overlap.y > self.y {
+            parts.push(Rect::new(self.x, self.y, self.width, overlap.y - self.y));
+        }
+        if let Ok(y) = u16::try_from(overlap.bottom()) {
+            if overlap.bottom() < self.bottom() {
+                let height = saturate(self.bottom() - overlap.bottom());
+                parts.push(Rect::new(self.x, y, self.width, height));
+            }
+        }
+        // ...and the pieces to its left and right cover the rows in between.
+        if overlap.x > self.x {
+            parts.push(Rect::new(
+                self.x,
+                overlap.y,
+                overlap.x - self.x,
+                overlap.height,
+            ));
+        }
+        if let Ok(x) = u16::try_from(overlap.right()) {
+            if overlap.right() < self.right() {
+                let width = saturate(self.right() - overlap.right());
+                parts.push(Rect::new(x, overlap.y, width, overlap.height));
+            }
+        }
+        parts
+    }
+}
+
+/// Clamp a length to the largest a rectangle can have.
+fn saturate(n: u32) -> u16 {
+    u16::try_from(n).unwrap_or(u16::MAX)
+}
+
+/// A set of non-overlapping rectangles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    rects: Vec<Rect>,
+}
+
+impl Region {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    /// The total area covered by the region.
+    pub fn area(&self) -> u64 {
+        self.rects.iter().map(Rect::area).sum()
+    }
+
+    /// The smallest rectangle containing the whole region.
+    pub fn bounds(&self) -> Rect {
+        self.rects
+            .iter()
+            .fold(Rect::default(), |bounds, r| bounds.union(r))
+    }
+
+    /// Add `rect` to the region.
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() || self.rects.iter().any(|r| r.contains(&rect)) {
+            return;
+        }
+        self.rects.retain(|r| !rect.contains(r));
+
+        // Only add the parts of the rectangle that aren't already covered, so the region stays
+        // free of overlaps.
+        let mut parts = vec![rect];
+        for existing in &self.rects {
+            parts = parts.iter().flat_map(|p| p.subtract(existing)).collect();
+        }
+        self.rects.extend(parts);
+
+        if self.rects.len() > MAX_RECTS {
+            self.rects = vec![self.bounds()];
+        }
+    }
+
+    /// Add every rectangle of `other` to the region.
+    pub fn union(&mut self, other: &Region) {
+        for r in &other.rects {
+            self.add(*r);
+        }
+    }
+
+    /// Restrict the region to the parts inside `rect`.
+    pub fn intersect(&mut self, rect: &Rect) {
+        self.rects = self
+            .rects
+            .iter()
+            .filter_map(|r| r.intersection(rect))
+            .collect();
+    }
+
+    /// The parts of the region that also lie in `other`.
+    pub fn intersection(&self, other: &Region) -> Region {
+        let mut out = Region::new();
+        for a in &self.rects {
+            for b in &other.rects {
+                if let Some(r) = a.intersection(b) {
+                    out.add(r);
+                }
+            }
+        }
+        out
+    }
+
+    /// Remove the parts of the region inside `rect`.
+    pub fn subtract(&mut self, rect: &Rect) {
+        self.rects = self.rects.iter().flat_map(|r| r.subtract(rect)).collect();
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    /// Empty the region, returning what it held.
+    pub fn take(&mut self) -> Region {
+        std::mem::take(self)
+    }
+}
+
+impl From<Rect> for Region {
+    fn from(rect: Rect) -> Self {
+        let mut region = Region::new();
+        region.add(rect);
+        region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rect, Region};
+
+    #[test]
+    fn test_rect_ops() {
+        let a = Rect::new(0, 0, 10, 10);
+        let b = Rect::new(5, 5, 10, 10);
+        assert_eq!(a.intersection(&b), Some(Rect::new(5, 5, 5, 5)));
+        assert_eq!(a.intersection(&Rect::new(10, 0, 5, 5)), None);
+        assert_eq!(a.union(&b), Rect::new(0, 0, 15, 15));
+
+        // Subtracting a hole from the middle leaves four pieces covering the rest.
+        let parts = a.subtract(&Rect::new(2, 2, 6, 6));
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts.iter().map(Rect::area).sum::<u64>(), 100 - 36);
+        assert_eq!(a.subtract(&a), vec![]);
+    }
+
+    #[test]
+    fn test_rect_ops_at_edge_of_coordinates() {
+        // Rectangles reaching past the last coordinate don't wrap around when combined.
+        let a = Rect::new(0, 0, u16::MAX, u16::MAX);
+        let b = Rect::new(100, 100, u16::MAX, u16::MAX);
+        assert_eq!(a.union(&b), Rect::new(0, 0, u16::MAX, u16::MAX));
+
+        // What's left of `b` past the last coordinate is dropped rather than moved to the start.
+        let parts = b.subtract(&a);
+        assert!(
+            parts.iter().all(|r| r.x >= 100 && r.y >= 100),
+            "{:?}",
+            parts
+        );
+        let parts = b.subtract(&Rect::new(100, 100, 200, 200));
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|r| r.intersection(&b) == Some(*r)));
+    }
+
+    #[test]
+    fn test_region_stays_disjoint() {
+        let mut region = Region::new();
+        region.add(Rect::new(0, 0, 10, 10));
+        region.add(Rect::new(5, 5, 10, 10));
+        assert_eq!(region.area(), 100 + 100 - 25);
+        assert_eq!(region.bounds(), Rect::new(0, 0, 15, 15));
+
+        // A rectangle already covered adds nothing, and one covering everything replaces it.
+        region.add(Rect::new(1, 1, 2, 2));
+        assert_eq!(region.area(), 175);
+        region.add(Rect::new(0, 0, 20, 20));
+        assert_eq!(region.rects(), &[Rect::new(0, 0, 20, 20)]);
+
+        let other = Region::from(Rect::new(15, 0, 10, 40));
+        assert_eq!(
+            region.intersection(&other).rects(),
+            &[Rect::new(15, 0, 5, 20)]
+        );
+
+        region.intersect(&Rect::new(10, 10, 20, 20));
+        assert_eq!(region.rects(), &[Rect::new(10, 10, 10, 10)]);
+        region.subtract(&Rect::new(10, 10, 10, 10));
+        assert!(region.is_empty());
+    }
+}
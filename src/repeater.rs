@@ -0,0 +1,343 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! UltraVNC repeater support
+//!
+//! A repeater brokers connections between viewers and servers that can't reach each other
+//! directly. The server connects out to the repeater and sends a 250-byte preamble saying which
+//! viewer it's for, after which the repeater relays the connection and the normal RFB handshake
+//! takes place over it. This is the repeater's mode II, in which the preamble is an `ID:nnnn`
+//! string that a viewer presents as well.
+//!
+//! Mode I needs no support here: the viewer names the server's `host:port` and the repeater
+//! connects to the server's own listener.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::server::{Server, ServerError, ServerFactory, ServerState, VncServer};
+
+/// Length of the preamble sent to the repeater, including zero padding.
+const PREAMBLE_LEN: usize = 250;
+
+/// The preamble announcing `id` to the repeater: `ID:nnnn`, zero padded.
+fn preamble(id: u32) -> [u8; PREAMBLE_LEN] {
+    let s = format!("ID:{}", id);
+    let mut buf = [0u8; PREAMBLE_LEN];
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    buf
+}
+
+/// Where and how to register with a repeater.
+#[derive(Debug, Clone)]
+pub struct RepeaterConfig {
+    pub addr: SocketAddr,
+
+    /// The id to register as, which viewers use to reach this server.
+    pub id: u32,
+
+    /// How long to wait for the repeater to accept a connection.
+    pub connect_timeout: Duration,
+
+    /// How long to wait before reconnecting after a failed connection attempt. This doubles with
+    /// each consecutive failure, up to `max_retry_delay`.
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+
+    /// How long a connection to the repeater must last to count as a success, resetting the
+    /// retry delay. Shorter ones count as failures, so a repeater that accepts connections and
+    /// drops them straight away isn't reconnected to in a tight loop.
+    pub min_session: Duration,
+}
+
+impl RepeaterConfig {
+    pub fn new(addr: SocketAddr, id: u32) -> Self {
+        Self {
+            addr,
+            id,
+            connect_timeout: Duration::from_secs(10),
+            retry_delay: Duration::from_secs(1),
+            max_retry_delay: Duration::from_secs(60),
+            min_session: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Wraps a stream to signal when it is dropped, which happens once the connection task serving
+/// it has finished.
+struct NotifyOnDrop<T> {
+    inner: T,
+    _done: oneshot::Sender<()>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for NotifyOnDrop<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for NotifyOnDrop<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Server, F: ServerFactory<Server = S>> VncServer<S, F> {
+    /// Register with the repeater described by `config`, serving each viewer it pairs with this
+    /// server in turn.
+    ///
+    /// Once a session through the repeater ends, or if the repeater can't be reached, the server
+    /// connects again. The server must be running; this future runs until it stops.
+    ///
+    /// While waiting for a viewer, a registration isn't subject to the idle timeout, which only
+    /// starts once the viewer begins the handshake.
+    pub async fn connect_repeater(
+        self: &Arc<Self>,
+        config: RepeaterConfig,
+    ) -> Result<(), ServerError> {
+        if self.state() != ServerState::Running {
+            return Err(ServerError::NotRunning);
+        }
+        let preamble = preamble(config.id);
+        let mut delay = config.retry_delay;
+
+        while self.state() == ServerState::Running {
+            let connect = TcpStream::connect(config.addr);
+            let connect = tokio::time::timeout(config.connect_timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+            let mut stream = match connect {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(
+                        "could not connect to repeater {:?}, retrying in {:?}: {}",
+                        config.addr, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(config.max_retry_delay);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.write_all(&preamble).await {
+                warn!("could not register with repeater {:?}: {}", config.addr, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(config.max_retry_delay);
+                continue;
+            }
+            info!(
+                "registered with repeater {:?} as {}",
+                config.addr, config.id
+            );
+            let started = Instant::now();
+
+            // The repeater holds the connection until a viewer is paired with it, then relays the
+            // RFB handshake as usual. That may take any time, so the idle timeout waits for the
+            // viewer.
+            let (done_tx, done_rx) = oneshot::channel();
+            let stream = NotifyOnDrop {
+                inner: stream,
+                _done: done_tx,
+            };
+            match self.serve_conn(Box::new(stream), config.addr.into(), true) {
+                Ok(()) => {}
+                Err(ServerError::NotRunning) => break,
+                Err(e) => return Err(e),
+            }
+            let _ = done_rx.await;
+
+            let lasted = started.elapsed();
+            if lasted >= config.min_session {
+                delay = config.retry_delay;
+            } else {
+                warn!(
+                    "connection to repeater {:?} closed after {:?}, reconnecting in {:?}",
+                    config.addr, lasted, delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(config.max_retry_delay);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{preamble, RepeaterConfig};
+    use crate::server::{ServerError, ServerState, VncServer, VncServerConfig};
+    use crate::testing::{test_config, test_server_with, TestServer};
+
+    #[test]
+    fn test_preamble() {
+        let p = preamble(1234);
+        assert_eq!(&p[..7], b"ID:1234");
+        assert!(p[7..].iter().all(|&b| b == 0));
+
+        let p = preamble(u32::MAX);
+        assert_eq!(&p[..13], b"ID:4294967295");
+    }
+
+    /// Start a server without a listener of its own, for serving repeater connections.
+    async fn run(
+        idle_timeout: Option<Duration>,
+    ) -> (Arc<VncServer<TestServer>>, tokio::task::JoinHandle<()>) {
+        let config = VncServerConfig {
+            idle_timeout,
+            ..test_config()
+        };
+        let server = test_server_with(config);
+        let s = server.clone();
+        let task = tokio::spawn(async move { s.start_without_listener().await.unwrap() });
+        while server.state() != ServerState::Running {
+            tokio::task::yield_now().await;
+        }
+        (server, task)
+    }
+
+    #[tokio::test]
+    async fn test_repeater_reconnects() {
+        let repeater = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (server, task) = run(None).await;
+
+        let s = server.clone();
+        let repeater_config = RepeaterConfig {
+            retry_delay: Duration::from_millis(10),
+            ..RepeaterConfig::new(repeater.local_addr().unwrap(), 42)
+        };
+        let registration = tokio::spawn(async move { s.connect_repeater(repeater_config).await });
+
+        // Each session starts with the preamble, followed by the server's protocol version.
+        for _ in 0..2 {
+            let (mut conn, _) = repeater.accept().await.unwrap();
+            let mut preamble = [0u8; 250];
+            conn.read_exact(&mut preamble).await.unwrap();
+            assert_eq!(&preamble[..5], b"ID:42");
+            let mut version = [0u8; 12];
+            conn.read_exact(&mut version).await.unwrap();
+            assert_eq!(&version, b"RFB 003.008\n");
+        }
+
+        server.stop().unwrap();
+        task.await.unwrap();
+        registration.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repeater_backoff() {
+        let repeater = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (server, task) = run(None).await;
+
+        let s = server.clone();
+        let repeater_config = RepeaterConfig {
+            retry_delay: Duration::from_millis(20),
+            ..RepeaterConfig::new(repeater.local_addr().unwrap(), 42)
+        };
+        let registration = tokio::spawn(async move { s.connect_repeater(repeater_config).await });
+
+        // A repeater that drops every connection as soon as it has the preamble is reconnected
+        // to less and less often.
+        let mut accepted = Vec::new();
+        for _ in 0..4 {
+            let (mut conn, _) = repeater.accept().await.unwrap();
+            let mut preamble = [0u8; 250];
+            conn.read_exact(&mut preamble).await.unwrap();
+            accepted.push(Instant::now());
+        }
+        let gaps: Vec<_> = accepted.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(gaps[0] >= Duration::from_millis(20), "{:?}", gaps);
+        assert!(gaps[2] >= Duration::from_millis(80), "{:?}", gaps);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+        registration.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repeater_not_running() {
+        let (server, task) = run(None).await;
+        server.stop().unwrap();
+        task.await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        assert!(matches!(
+            server.connect_repeater(RepeaterConfig::new(addr, 42)).await,
+            Err(ServerError::NotRunning)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_repeater_waits_for_viewer() {
+        let repeater = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (server, task) = run(Some(Duration::from_millis(50))).await;
+
+        let s = server.clone();
+        let repeater_config = RepeaterConfig {
+            retry_delay: Duration::from_millis(10),
+            ..RepeaterConfig::new(repeater.local_addr().unwrap(), 42)
+        };
+        let registration = tokio::spawn(async move { s.connect_repeater(repeater_config).await });
+
+        let (mut conn, _) = repeater.accept().await.unwrap();
+        let mut preamble = [0u8; 250];
+        conn.read_exact(&mut preamble).await.unwrap();
+        let mut version = [0u8; 12];
+        conn.read_exact(&mut version).await.unwrap();
+
+        // Waiting for a viewer for longer than the idle timeout neither closes the registration
+        // nor makes the server register again.
+        let reconnect = tokio::time::timeout(Duration::from_millis(200), repeater.accept()).await;
+        assert!(reconnect.is_err());
+
+        // Once the viewer answers, the handshake goes on as usual.
+        conn.write_all(b"RFB 003.008\n").await.unwrap();
+        let mut sec_types = [0u8; 2];
+        conn.read_exact(&mut sec_types).await.unwrap();
+        assert_eq!(sec_types, [1, 1]);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+        registration.await.unwrap().unwrap();
+    }
+}
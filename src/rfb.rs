@@ -8,12 +8,12 @@ use bitflags::bitflags;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::encodings::{Encoding, EncodingType};
 use crate::keysym::KeySym;
 use crate::pixel_formats::rgb_888;
+use crate::region::Rect;
 
 #[derive(Debug, Error)]
 pub enum ProtocolError {
@@ -29,6 +29,15 @@ pub enum ProtocolError {
     #[error("unknown client message type ({0})")]
     UnknownClientMessageType(u8),
 
+    #[error("unknown server message type ({0})")]
+    UnknownServerMessageType(u8),
+
+    #[error("connection refused by server: {0}")]
+    ConnectionRefused(String),
+
+    #[error("fence payload of {0} bytes is too long")]
+    FencePayloadTooLong(u8),
+
     #[error(transparent)]
     KeySymError(#[from] crate::keysym::KeySymError),
 
@@ -36,14 +45,32 @@ pub enum ProtocolError {
     Io(#[from] std::io::Error),
 }
 
+/// A stream RFB messages can be read from.
+pub trait ReadStream: AsyncRead + Unpin + Send {}
+
+impl<T: AsyncRead + Unpin + Send + ?Sized> ReadStream for T {}
+
+/// A stream RFB messages can be written to.
+pub trait WriteStream: AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncWrite + Unpin + Send + ?Sized> WriteStream for T {}
+
+/// A bidirectional stream an RFB session can run over.
+pub trait RfbStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized> RfbStream for T {}
+
 pub trait ReadMessage {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>>
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>>
     where
         Self: Sized;
 }
 
 pub trait WriteMessage {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>>;
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>>;
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -54,7 +81,7 @@ pub enum ProtoVersion {
 }
 
 impl ReadMessage for ProtoVersion {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async move {
             let mut buf = [0u8; 12];
             stream.read_exact(&mut buf).await?;
@@ -71,7 +98,10 @@ impl ReadMessage for ProtoVersion {
 }
 
 impl WriteMessage for ProtoVersion {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             let s = match self {
                 ProtoVersion::Rfb33 => b"RFB 003.003\n",
@@ -96,7 +126,10 @@ pub enum SecurityType {
 }
 
 impl WriteMessage for SecurityTypes {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             // TODO: fix cast
             stream.write_u8(self.0.len() as u8).await?;
@@ -110,8 +143,34 @@ impl WriteMessage for SecurityTypes {
     }
 }
 
+impl ReadMessage for SecurityTypes {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+        async move {
+            let n = stream.read_u8().await?;
+
+            // A server that refuses the connection sends no types, followed by a reason.
+            if n == 0 {
+                return Err(ProtocolError::ConnectionRefused(read_string(stream).await?));
+            }
+
+            let mut types = Vec::new();
+            for _ in 0..n {
+                // Skip types we don't know, so the client can pick one it does.
+                match SecurityType::read_from(stream).await {
+                    Ok(t) => types.push(t),
+                    Err(ProtocolError::InvalidSecurityType(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Ok(SecurityTypes(types))
+        }
+        .boxed()
+    }
+}
+
 impl ReadMessage for SecurityType {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async move {
             let t = stream.read_u8().await?;
             match t {
@@ -125,11 +184,14 @@ impl ReadMessage for SecurityType {
 }
 
 impl WriteMessage for SecurityType {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             let val = match self {
-                SecurityType::None => 0,
-                SecurityType::VncAuthentication => 1,
+                SecurityType::None => 1,
+                SecurityType::VncAuthentication => 2,
             };
             stream.write_u8(val).await?;
 
@@ -139,6 +201,30 @@ impl WriteMessage for SecurityType {
     }
 }
 
+/// Sent in place of the list of security types to tell the client the connection failed, and why.
+///
+/// This is a security types message with zero types, followed by a reason string.
+pub struct ConnectionFailure(pub String);
+
+impl WriteMessage for ConnectionFailure {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        async move {
+            // number-of-security-types is zero
+            stream.write_u8(0).await?;
+
+            // TODO: cast properly
+            stream.write_u32(self.0.len() as u32).await?;
+            stream.write_all(self.0.as_bytes()).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
 // Section 7.1.3
 pub enum SecurityResult {
     Success,
@@ -146,7 +232,10 @@ pub enum SecurityResult {
 }
 
 impl WriteMessage for SecurityResult {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             match self {
                 SecurityResult::Success => {
@@ -154,6 +243,8 @@ impl WriteMessage for SecurityResult {
                 }
                 SecurityResult::Failure(s) => {
                     stream.write_u32(1).await?;
+                    // TODO: cast properly
+                    stream.write_u32(s.len() as u32).await?;
                     stream.write_all(s.as_bytes()).await?;
                 }
             };
@@ -164,14 +255,64 @@ impl WriteMessage for SecurityResult {
     }
 }
 
+/// Reads a security result in the RFB 3.8 format, where a failure is followed by a reason.
+impl ReadMessage for SecurityResult {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+        async move {
+            match stream.read_u32().await? {
+                0 => Ok(SecurityResult::Success),
+                _ => Ok(SecurityResult::Failure(read_string(stream).await?)),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Reads a 32-bit length followed by that many bytes.
+async fn read_sized(stream: &mut dyn ReadStream) -> Result<Vec<u8>, ProtocolError> {
+    let len = stream.read_u32().await?;
+    // Let the buffer grow as the data arrives, rather than trusting the length up front.
+    let mut buf = Vec::new();
+    (&mut *stream)
+        .take(len as u64)
+        .read_to_end(&mut buf)
+        .await?;
+    if buf.len() != len as usize {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+/// Reads text sent as a 32-bit length followed by that many ISO 8859-1 (Latin-1) bytes, as cut
+/// text is.
+pub(crate) async fn read_latin1(stream: &mut dyn ReadStream) -> Result<String, ProtocolError> {
+    let buf = read_sized(stream).await?;
+    Ok(buf.into_iter().map(char::from).collect())
+}
+
+/// Reads a string sent as a 32-bit length followed by that many bytes.
+pub(crate) async fn read_string(stream: &mut dyn ReadStream) -> Result<String, ProtocolError> {
+    let buf = read_sized(stream).await?;
+    String::from_utf8(buf).map_err(|_| ProtocolError::InvalidTextEncoding)
+}
+
 // Section 7.3.1
 #[derive(Debug)]
 pub struct ClientInit {
     pub shared: bool,
 }
 
+impl WriteMessage for ClientInit {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        async move { Ok(stream.write_u8(self.shared as u8).await?) }.boxed()
+    }
+}
+
 impl ReadMessage for ClientInit {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let flag = stream.read_u8().await?;
             match flag {
@@ -199,10 +340,46 @@ impl ServerInit {
             name,
         }
     }
+
+    pub fn width(&self) -> u16 {
+        self.initial_res.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.initial_res.height
+    }
+
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.pixel_format
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl ReadMessage for ServerInit {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+        async move {
+            let initial_res = Resolution::read_from(stream).await?;
+            let pixel_format = PixelFormat::read_from(stream).await?;
+            let name = read_string(stream).await?;
+
+            Ok(ServerInit {
+                initial_res,
+                pixel_format,
+                name,
+            })
+        }
+        .boxed()
+    }
 }
 
 impl WriteMessage for ServerInit {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             self.initial_res.write_to(stream).await?;
             self.pixel_format.write_to(stream).await?;
@@ -217,11 +394,67 @@ impl WriteMessage for ServerInit {
     }
 }
 
-pub enum _ServerMessage {
+// Section 7.6
+pub enum ServerMessage {
     FramebufferUpdate(FramebufferUpdate),
     SetColorMapEntries(SetColorMapEntries),
     Bell,
     ServerCutText(CutText),
+    EndOfContinuousUpdates,
+    Fence(Fence),
+}
+
+impl WriteMessage for ServerMessage {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        async move {
+            match self {
+                ServerMessage::FramebufferUpdate(fbu) => fbu.write_to(stream).await?,
+                ServerMessage::SetColorMapEntries(e) => {
+                    stream.write_u8(1).await?;
+
+                    // 1 byte of padding
+                    stream.write_u8(0).await?;
+
+                    stream.write_u16(e.first_color).await?;
+                    // TODO: cast properly
+                    stream.write_u16(e.colors.len() as u16).await?;
+                    for c in e.colors {
+                        stream.write_u16(c.red).await?;
+                        stream.write_u16(c.green).await?;
+                        stream.write_u16(c.blue).await?;
+                    }
+                }
+                ServerMessage::Bell => {
+                    stream.write_u8(2).await?;
+                }
+                ServerMessage::ServerCutText(t) => {
+                    stream.write_u8(3).await?;
+
+                    // 3 bytes of padding
+                    let padding = [0u8; 3];
+                    stream.write_all(&padding).await?;
+
+                    let buf = t.to_latin1();
+                    // TODO: cast properly
+                    stream.write_u32(buf.len() as u32).await?;
+                    stream.write_all(&buf).await?;
+                }
+                ServerMessage::EndOfContinuousUpdates => {
+                    stream.write_u8(150).await?;
+                }
+                ServerMessage::Fence(f) => {
+                    stream.write_u8(248).await?;
+                    f.write_to(stream).await?;
+                }
+            };
+
+            Ok(())
+        }
+        .boxed()
+    }
 }
 
 pub struct FramebufferUpdate {
@@ -233,6 +466,14 @@ impl FramebufferUpdate {
         FramebufferUpdate { rectangles }
     }
 
+    pub fn rectangles(&self) -> &[Rectangle] {
+        &self.rectangles
+    }
+
+    pub fn into_rectangles(self) -> Vec<Rectangle> {
+        self.rectangles
+    }
+
     pub fn transform(&self, input_pf: &PixelFormat, output_pf: &PixelFormat) -> Self {
         let mut rectangles = Vec::new();
 
@@ -251,7 +492,7 @@ pub(crate) struct Position {
 }
 
 impl ReadMessage for Position {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let x = stream.read_u16().await?;
             let y = stream.read_u16().await?;
@@ -269,7 +510,7 @@ pub(crate) struct Resolution {
 }
 
 impl ReadMessage for Resolution {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let width = stream.read_u16().await?;
             let height = stream.read_u16().await?;
@@ -281,7 +522,10 @@ impl ReadMessage for Resolution {
 }
 
 impl WriteMessage for Resolution {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             stream.write_u16(self.width).await?;
             stream.write_u16(self.height).await?;
@@ -306,6 +550,24 @@ impl Rectangle {
         }
     }
 
+    /// The area of the framebuffer the rectangle covers.
+    pub fn rect(&self) -> Rect {
+        Rect::new(
+            self.position.x,
+            self.position.y,
+            self.dimensions.width,
+            self.dimensions.height,
+        )
+    }
+
+    pub fn encoding_type(&self) -> EncodingType {
+        self.data.get_type()
+    }
+
+    pub fn data(&self) -> &dyn Encoding {
+        self.data.as_ref()
+    }
+
     pub fn transform(&self, input_pf: &PixelFormat, output_pf: &PixelFormat) -> Self {
         Rectangle {
             position: self.position,
@@ -316,7 +578,10 @@ impl Rectangle {
 }
 
 impl WriteMessage for Rectangle {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             let encoding_type: i32 = self.data.get_type().into();
 
@@ -336,7 +601,10 @@ impl WriteMessage for Rectangle {
 }
 
 impl WriteMessage for FramebufferUpdate {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             // TODO: type function?
             stream.write_u8(0).await?;
@@ -359,24 +627,40 @@ impl WriteMessage for FramebufferUpdate {
     }
 }
 
+// Section 7.6.2
 #[derive(Debug)]
 pub struct SetColorMapEntries {
-    _colors: Vec<_ColorMapEntry>,
+    /// The index of the first colour map entry being set.
+    pub first_color: u16,
+    pub colors: Vec<ColorMapEntry>,
 }
 
-#[derive(Debug)]
-pub struct _ColorMapEntry {
-    _color: u16,
-    _red: u16,
-    _blue: u16,
-    _green: u16,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ColorMapEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
 }
 
 // TODO: only ISO 8859-1 (Latin-1) text supported
 // used for client and server
 #[derive(Debug)]
 pub struct CutText {
-    _text: String,
+    text: String,
+}
+
+impl CutText {
+    pub fn new(text: String) -> Self {
+        CutText { text }
+    }
+
+    /// Encode the text as ISO 8859-1 (Latin-1), replacing characters outside of it with '?'.
+    fn to_latin1(&self) -> Vec<u8> {
+        self.text
+            .chars()
+            .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+            .collect()
+    }
 }
 
 // Section 7.4
@@ -390,6 +674,7 @@ pub struct PixelFormat {
 
 impl PixelFormat {
     /// Constructor for a PixelFormat that uses a color format to specify colors.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_colorformat(
         bbp: u8,
         depth: u8,
@@ -437,15 +722,12 @@ impl PixelFormat {
 }
 
 impl ReadMessage for PixelFormat {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let bits_per_pixel = stream.read_u8().await?;
             let depth = stream.read_u8().await?;
             let be_flag = stream.read_u8().await?;
-            let big_endian = match be_flag {
-                0 => false,
-                _ => true,
-            };
+            let big_endian = be_flag != 0;
             let color_spec = ColorSpecification::read_from(stream).await?;
 
             // 3 bytes of padding
@@ -464,7 +746,10 @@ impl ReadMessage for PixelFormat {
 }
 
 impl WriteMessage for PixelFormat {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             stream.write_u8(self.bits_per_pixel).await?;
             stream.write_u8(self.depth).await?;
@@ -503,7 +788,7 @@ pub struct ColorFormat {
 pub struct ColorMap {}
 
 impl ReadMessage for ColorSpecification {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let tc_flag = stream.read_u8().await?;
             match tc_flag {
@@ -537,7 +822,10 @@ impl ReadMessage for ColorSpecification {
 }
 
 impl WriteMessage for ColorSpecification {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<(), ProtocolError>> {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
         async move {
             match self {
                 ColorSpecification::ColorFormat(cf) => {
@@ -569,11 +857,29 @@ pub enum ClientMessage {
     KeyEvent(KeyEvent),
     PointerEvent(PointerEvent),
     ClientCutText(String),
+    EnableContinuousUpdates(EnableContinuousUpdates),
+    Fence(Fence),
+}
+
+impl ClientMessage {
+    /// The name of the message type, for diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientMessage::SetPixelFormat(_) => "SetPixelFormat",
+            ClientMessage::SetEncodings(_) => "SetEncodings",
+            ClientMessage::FramebufferUpdateRequest(_) => "FramebufferUpdateRequest",
+            ClientMessage::KeyEvent(_) => "KeyEvent",
+            ClientMessage::PointerEvent(_) => "PointerEvent",
+            ClientMessage::ClientCutText(_) => "ClientCutText",
+            ClientMessage::EnableContinuousUpdates(_) => "EnableContinuousUpdates",
+            ClientMessage::Fence(_) => "Fence",
+        }
+    }
 }
 
 impl ReadMessage for ClientMessage {
     fn read_from<'a>(
-        stream: &'a mut TcpStream,
+        stream: &'a mut dyn ReadStream,
     ) -> BoxFuture<'a, Result<ClientMessage, ProtocolError>> {
         async {
             let t = stream.read_u8().await?;
@@ -603,10 +909,7 @@ impl ReadMessage for ClientMessage {
                 }
                 3 => {
                     // FramebufferUpdateRequest
-                    let incremental = match stream.read_u8().await? {
-                        0 => false,
-                        _ => true,
-                    };
+                    let incremental = stream.read_u8().await? != 0;
                     let position = Position::read_from(stream).await?;
                     let resolution = Resolution::read_from(stream).await?;
 
@@ -620,10 +923,7 @@ impl ReadMessage for ClientMessage {
                 }
                 4 => {
                     // KeyEvent
-                    let is_pressed = match stream.read_u8().await? {
-                        0 => false,
-                        _ => true,
-                    };
+                    let is_pressed = stream.read_u8().await? != 0;
 
                     // 2 bytes of padding
                     stream.read_u16().await?;
@@ -651,17 +951,27 @@ impl ReadMessage for ClientMessage {
                     let mut padding = [0u8; 3];
                     stream.read_exact(&mut padding).await?;
 
-                    let len = stream.read_u32().await?;
-                    let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
-                    stream.read_exact(&mut buf).await?;
-
-                    // TODO: The encoding RFB uses is ISO 8859-1 (Latin-1), which is a subset of
-                    // utf-8. Determine if this is the right approach.
-                    let text =
-                        String::from_utf8(buf).map_err(|_| ProtocolError::InvalidTextEncoding)?;
-
+                    let text = read_latin1(stream).await?;
                     Ok(ClientMessage::ClientCutText(text))
                 }
+                150 => {
+                    // EnableContinuousUpdates
+                    let enable = stream.read_u8().await? != 0;
+                    let position = Position::read_from(stream).await?;
+                    let resolution = Resolution::read_from(stream).await?;
+                    Ok(ClientMessage::EnableContinuousUpdates(
+                        EnableContinuousUpdates {
+                            enable,
+                            position,
+                            resolution,
+                        },
+                    ))
+                }
+                248 => {
+                    // Fence
+                    let fence = Fence::read_from(stream).await?;
+                    Ok(ClientMessage::Fence(fence))
+                }
                 unknown => Err(ProtocolError::UnknownClientMessageType(unknown)),
             };
 
@@ -671,6 +981,85 @@ impl ReadMessage for ClientMessage {
     }
 }
 
+impl WriteMessage for ClientMessage {
+    fn write_to<'a>(
+        self,
+        stream: &'a mut dyn WriteStream,
+    ) -> BoxFuture<'a, Result<(), ProtocolError>> {
+        async move {
+            match self {
+                ClientMessage::SetPixelFormat(pf) => {
+                    stream.write_u8(0).await?;
+
+                    // 3 bytes of padding
+                    stream.write_all(&[0u8; 3]).await?;
+
+                    pf.write_to(stream).await?;
+                }
+                ClientMessage::SetEncodings(encodings) => {
+                    stream.write_u8(2).await?;
+
+                    // 1 byte of padding
+                    stream.write_u8(0).await?;
+
+                    // TODO: cast properly
+                    stream.write_u16(encodings.len() as u16).await?;
+                    for e in encodings {
+                        stream.write_i32(e.into()).await?;
+                    }
+                }
+                ClientMessage::FramebufferUpdateRequest(req) => {
+                    stream.write_u8(3).await?;
+                    stream.write_u8(req.incremental as u8).await?;
+                    stream.write_u16(req.position.x).await?;
+                    stream.write_u16(req.position.y).await?;
+                    req.resolution.write_to(stream).await?;
+                }
+                ClientMessage::KeyEvent(ke) => {
+                    stream.write_u8(4).await?;
+                    stream.write_u8(ke.is_pressed as u8).await?;
+
+                    // 2 bytes of padding
+                    stream.write_u16(0).await?;
+
+                    stream.write_u32(ke.keysym_raw).await?;
+                }
+                ClientMessage::PointerEvent(pe) => {
+                    stream.write_u8(5).await?;
+                    stream.write_u8(pe.pressed.bits()).await?;
+                    stream.write_u16(pe.position.x).await?;
+                    stream.write_u16(pe.position.y).await?;
+                }
+                ClientMessage::ClientCutText(text) => {
+                    stream.write_u8(6).await?;
+
+                    // 3 bytes of padding
+                    stream.write_all(&[0u8; 3]).await?;
+
+                    let buf = CutText::new(text).to_latin1();
+                    // TODO: cast properly
+                    stream.write_u32(buf.len() as u32).await?;
+                    stream.write_all(&buf).await?;
+                }
+                ClientMessage::EnableContinuousUpdates(c) => {
+                    stream.write_u8(150).await?;
+                    stream.write_u8(c.enable as u8).await?;
+                    stream.write_u16(c.position.x).await?;
+                    stream.write_u16(c.position.y).await?;
+                    c.resolution.write_to(stream).await?;
+                }
+                ClientMessage::Fence(f) => {
+                    stream.write_u8(248).await?;
+                    f.write_to(stream).await?;
+                }
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct FramebufferUpdateRequest {
@@ -679,6 +1068,32 @@ pub struct FramebufferUpdateRequest {
     resolution: Resolution,
 }
 
+impl FramebufferUpdateRequest {
+    pub fn new(incremental: bool, x: u16, y: u16, width: u16, height: u16) -> Self {
+        FramebufferUpdateRequest {
+            incremental,
+            position: Position { x, y },
+            resolution: Resolution { width, height },
+        }
+    }
+
+    /// Whether the client only wants the parts of the framebuffer that changed since its last
+    /// update.
+    pub fn incremental(&self) -> bool {
+        self.incremental
+    }
+
+    /// The area of the framebuffer the client wants updated.
+    pub fn rect(&self) -> Rect {
+        Rect::new(
+            self.position.x,
+            self.position.y,
+            self.resolution.width,
+            self.resolution.height,
+        )
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct KeyEvent {
     is_pressed: bool,
@@ -700,8 +1115,94 @@ impl KeyEvent {
     }
 }
 
+/// Starts or stops updates of a region being sent without the client asking for each one, as
+/// part of the ContinuousUpdates extension.
+#[derive(Debug)]
+pub struct EnableContinuousUpdates {
+    enable: bool,
+    position: Position,
+    resolution: Resolution,
+}
+
+impl EnableContinuousUpdates {
+    pub fn new(enable: bool, x: u16, y: u16, width: u16, height: u16) -> Self {
+        EnableContinuousUpdates {
+            enable,
+            position: Position { x, y },
+            resolution: Resolution { width, height },
+        }
+    }
+
+    /// Whether updates should start, or stop.
+    pub fn enable(&self) -> bool {
+        self.enable
+    }
+
+    /// The area of the framebuffer to keep the client up to date on.
+    pub fn rect(&self) -> Rect {
+        Rect::new(
+            self.position.x,
+            self.position.y,
+            self.resolution.width,
+            self.resolution.height,
+        )
+    }
+}
+
+bitflags! {
+    /// How a [`Fence`] orders the messages around it.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct FenceFlags: u32 {
+        /// Messages before the fence must be handled before it is answered.
+        const BLOCK_BEFORE = 1 << 0;
+        /// Messages after the fence must not be handled until it is answered.
+        const BLOCK_AFTER = 1 << 1;
+        /// The answer is sent after the next message is handled.
+        const SYNC_NEXT = 1 << 2;
+        /// The fence is a request to be answered, rather than an answer.
+        const REQUEST = 1 << 31;
+    }
+}
+
+/// A synchronisation point in the message stream, sent by either side as part of the Fence
+/// extension. A request is answered with a fence carrying the same payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fence {
+    pub flags: FenceFlags,
+    pub payload: Vec<u8>,
+}
+
+impl Fence {
+    /// The longest payload a fence may carry.
+    pub const MAX_PAYLOAD: usize = 64;
+
+    /// Reads a fence following its message type.
+    async fn read_from(stream: &mut dyn ReadStream) -> Result<Self, ProtocolError> {
+        let mut padding = [0u8; 3];
+        stream.read_exact(&mut padding).await?;
+        let flags = FenceFlags::from_bits_retain(stream.read_u32().await?);
+        let len = stream.read_u8().await?;
+        if len as usize > Self::MAX_PAYLOAD {
+            return Err(ProtocolError::FencePayloadTooLong(len));
+        }
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload).await?;
+        Ok(Fence { flags, payload })
+    }
+
+    /// Writes a fence following its message type.
+    async fn write_to(self, stream: &mut dyn WriteStream) -> Result<(), ProtocolError> {
+        stream.write_all(&[0u8; 3]).await?;
+        stream.write_u32(self.flags.bits()).await?;
+        let len = self.payload.len().min(Self::MAX_PAYLOAD);
+        stream.write_u8(len as u8).await?;
+        stream.write_all(&self.payload[..len]).await?;
+        Ok(())
+    }
+}
+
 bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Copy, Clone)]
     struct MouseButtons: u8 {
         const LEFT = 1 << 0;
         const MIDDLE = 1 << 1;
@@ -713,15 +1214,29 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
 pub struct PointerEvent {
     position: Position,
     pressed: MouseButtons,
 }
 
+impl PointerEvent {
+    pub fn x(&self) -> u16 {
+        self.position.x
+    }
+
+    pub fn y(&self) -> u16 {
+        self.position.y
+    }
+
+    /// The pressed buttons, one bit per button as sent on the wire.
+    pub fn button_mask(&self) -> u8 {
+        self.pressed.bits()
+    }
+}
+
 impl ReadMessage for PointerEvent {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
+    fn read_from<'a>(stream: &'a mut dyn ReadStream) -> BoxFuture<'a, Result<Self, ProtocolError>> {
         async {
             let button_mask = stream.read_u8().await?;
             let pressed = MouseButtons::from_bits_truncate(button_mask);
@@ -732,3 +1247,83 @@ impl ReadMessage for PointerEvent {
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_string, ColorMapEntry, Fence, FenceFlags, ProtocolError, SecurityResult, SecurityType,
+        SecurityTypes, ServerMessage, SetColorMapEntries, WriteMessage,
+    };
+
+    #[tokio::test]
+    async fn test_read_string() {
+        let mut data: &[u8] = &[0, 0, 0, 2, b'h', b'i'];
+        assert_eq!(read_string(&mut data).await.unwrap(), "hi");
+
+        // A length far beyond the data that follows is an error, not an allocation of that size.
+        let mut data: &[u8] = &[0xff, 0xff, 0xff, 0xff, b'h', b'i'];
+        match read_string(&mut data).await {
+            Err(ProtocolError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_security_types_wire_format() {
+        let mut buf = Vec::new();
+        SecurityTypes(vec![SecurityType::None, SecurityType::VncAuthentication])
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [2, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_security_result_wire_format() {
+        let mut buf = Vec::new();
+        SecurityResult::Success.write_to(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 0]);
+
+        // A failure carries its reason as a length-prefixed string.
+        let mut buf = Vec::new();
+        SecurityResult::Failure("no".to_string())
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 0, 0, 0, 2, b'n', b'o']);
+    }
+
+    #[tokio::test]
+    async fn test_set_color_map_entries_wire_format() {
+        let mut buf = Vec::new();
+        let entries = SetColorMapEntries {
+            first_color: 3,
+            colors: vec![ColorMapEntry {
+                red: 0x0102,
+                green: 0x0304,
+                blue: 0x0506,
+            }],
+        };
+        ServerMessage::SetColorMapEntries(entries)
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [1, 0, 0, 3, 0, 1, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_fence_wire_format() {
+        let mut data: &[u8] = &[0, 0, 0, 0x80, 0, 0, 1, 2, 1, 2];
+        let fence = Fence::read_from(&mut data).await.unwrap();
+        assert_eq!(fence.flags, FenceFlags::REQUEST | FenceFlags::BLOCK_BEFORE);
+        assert_eq!(fence.payload, [1, 2]);
+
+        // Payloads are limited to 64 bytes, even though the length would allow more.
+        let mut data = vec![0, 0, 0, 0, 0, 0, 0, 65];
+        data.extend_from_slice(&[0; 65]);
+        match Fence::read_from(&mut &data[..]).await {
+            Err(ProtocolError::FencePayloadTooLong(65)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -4,26 +4,49 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+use std::fmt;
+use std::future::Future;
 use std::io;
 use std::marker::{Send, Sync};
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use futures::FutureExt;
-use futures::future::Shared;
-use log::{debug, error, info, trace};
+use futures::{future, stream, StreamExt};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
-use tokio::sync::{Mutex, oneshot};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant as TokioInstant};
+use tracing::{
+    debug, debug_span, error, field, info, info_span, instrument, trace, warn, Instrument, Span,
+};
 
+use crate::clients::{
+    Authorization, Client, ClientChannels, ClientCommand, ClientContext, ClientHandle, ClientId,
+    ClientInfo, ClientRole, Connections, CountingStream,
+};
+use crate::damage::DamageNotifier;
+use crate::encodings::EncodingType;
+use crate::listener::{accept_any, ListenAddr, Listener, PeerAddr};
+use crate::metrics::ServerMetrics;
+use crate::region::Rect;
 use crate::rfb::{
-    ClientInit, ClientMessage, FramebufferUpdate, KeyEvent, PixelFormat, ProtoVersion,
-    ProtocolError, ReadMessage, SecurityResult, SecurityType, SecurityTypes, ServerInit,
-    WriteMessage,
+    ClientInit, ClientMessage, ConnectionFailure, CutText, Fence, FenceFlags, FramebufferUpdate,
+    KeyEvent, PixelFormat, PointerEvent, ProtoVersion, ProtocolError, ReadMessage, RfbStream,
+    SecurityResult, SecurityType, SecurityTypes, ServerInit, ServerMessage, WriteMessage,
+    WriteStream,
 };
+use crate::update::UpdateState;
+
+/// A client's socket, as seen by its connection task.
+type ClientStream = CountingStream<Box<dyn RfbStream>>;
+
+/// A client connection accepted outside of the server's own listener, and whether it waits for a
+/// peer before the handshake starts (see [`VncServer::handle_conn`]).
+type IncomingConn = (Box<dyn RfbStream>, PeerAddr, bool);
 
 #[derive(Debug, Error)]
 pub enum HandshakeError {
@@ -45,12 +68,351 @@ pub enum HandshakeError {
     Protocol(#[from] ProtocolError),
 }
 
+/// An error reported by a [`Server`] implementation.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum BackendError {
+    /// A temporary failure, such as the display not being ready yet. The operation is retried a
+    /// few times before the client is disconnected.
+    #[error("transient backend error: {0}")]
+    Transient(String),
+
+    /// The backend can't continue serving the client, and its session is closed.
+    #[error("backend error: {0}")]
+    Fatal(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("server is already running")]
+    AlreadyRunning,
+
+    #[error("server is not running")]
+    NotRunning,
+
+    #[error("client {0} is not connected")]
+    ClientDisconnected(ClientId),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// The lifecycle state of a [`VncServer`].
+///
+/// A server starts out `NotStarted`. [`VncServer::start`] moves it to `Running`, and a call to
+/// [`VncServer::stop`] or [`VncServer::stop_gracefully`] moves it to `Stopping` until every
+/// connection has been closed, at which point it is `Stopped`. A stopped server can be started
+/// again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServerState {
+    NotStarted,
+    Running,
+    Stopping,
+    Stopped,
+}
+
+/// The reason a client connection was closed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    /// The server was stopped.
+    ServerShutdown,
+
+    /// The server was stopped gracefully, but the connection did not finish before the drain
+    /// timeout elapsed.
+    DrainTimeout,
+
+    /// The client closed the connection.
+    ClientClosed,
+
+    /// The RFB handshake or initialization with the client could not be completed.
+    HandshakeFailed(String),
+
+    /// An error occurred reading from or writing to the client.
+    ProtocolError(String),
+
+    /// The server was at its connection limit and refused the connection.
+    ConnectionLimit,
+
+    /// The client was disconnected to make room for a new connection.
+    Evicted,
+
+    /// The [`Server`] implementation reported an error it couldn't recover from.
+    Backend(BackendError),
+
+    /// The client was disconnected through its [`ClientHandle`].
+    Disconnected,
+
+    /// The client sent no input or update requests for longer than the configured idle timeout.
+    IdleTimeout,
+
+    /// The client was connected for longer than the configured maximum session duration.
+    SessionExpired,
+
+    /// Another client was given exclusive access to the server.
+    Replaced,
+
+    /// The client asked for exclusive access while other clients were connected.
+    ExclusiveAccessDenied,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ServerShutdown => write!(f, "server shutting down"),
+            DisconnectReason::DrainTimeout => write!(f, "server shutdown drain timed out"),
+            DisconnectReason::ClientClosed => write!(f, "client closed connection"),
+            DisconnectReason::HandshakeFailed(e) => write!(f, "handshake failed: {}", e),
+            DisconnectReason::ProtocolError(e) => write!(f, "protocol error: {}", e),
+            DisconnectReason::ConnectionLimit => write!(f, "too many connections"),
+            DisconnectReason::Evicted => write!(f, "disconnected to make room for a new client"),
+            DisconnectReason::Backend(e) => write!(f, "{}", e),
+            DisconnectReason::Disconnected => write!(f, "disconnected by the server"),
+            DisconnectReason::IdleTimeout => write!(f, "idle timeout"),
+            DisconnectReason::SessionExpired => write!(f, "maximum session duration reached"),
+            DisconnectReason::Replaced => write!(f, "another client took exclusive access"),
+            DisconnectReason::ExclusiveAccessDenied => {
+                write!(f, "exclusive access refused, other clients are connected")
+            }
+        }
+    }
+}
+
+impl From<BackendError> for DisconnectReason {
+    fn from(e: BackendError) -> Self {
+        DisconnectReason::Backend(e)
+    }
+}
+
+impl From<ProtocolError> for DisconnectReason {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                DisconnectReason::ClientClosed
+            }
+            e => DisconnectReason::ProtocolError(e.to_string()),
+        }
+    }
+}
+
+/// Wait for a connection to be asked to close, returning the reason.
+async fn wait_for_close(
+    close_ch: &mut watch::Receiver<Option<DisconnectReason>>,
+) -> DisconnectReason {
+    match close_ch.wait_for(|r| r.is_some()).await {
+        Ok(r) => r.clone().unwrap(),
+        // The server went away without saying why.
+        Err(_) => DisconnectReason::ServerShutdown,
+    }
+}
+
+/// Wait up to `timeout` for every connection task in `conns` to exit, returning whether they did.
+async fn join_all(conns: &mut JoinSet<()>, timeout: Duration) -> bool {
+    let joined = tokio::time::timeout(timeout, async {
+        while conns.join_next().await.is_some() {}
+    });
+    joined.await.is_ok()
+}
+
+/// How long connections have to close once the server stops, before their tasks are aborted.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to stop accepting connections after an accept error that isn't specific to one
+/// connection.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether an accept error only affected the connection being accepted, so the next accept may
+/// well succeed.
+pub(crate) fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// How many times an operation that fails with [`BackendError::Transient`] is attempted.
+const BACKEND_ATTEMPTS: u32 = 5;
+
+/// How long to wait before the first retry of a transient backend failure. The delay doubles with
+/// each attempt.
+const BACKEND_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Call into the backend, retrying transient failures.
+async fn retry_transient<T, F, Fut>(mut f: F) -> Result<T, BackendError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, BackendError>>,
+{
+    let mut delay = BACKEND_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(BackendError::Transient(e)) if attempt < BACKEND_ATTEMPTS => {
+                warn!(
+                    attempt,
+                    "transient backend error (attempt {}/{}): {}", attempt, BACKEND_ATTEMPTS, e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Sleep until `deadline`, or forever if there isn't one.
+async fn sleep_until_opt(deadline: Option<TokioInstant>) {
+    match deadline {
+        Some(d) => sleep_until(d).await,
+        None => future::pending().await,
+    }
+}
+
+/// A request to stop the server, sent from [`VncServer::stop`] or [`VncServer::stop_gracefully`]
+/// to the accept loop in [`VncServer::start`].
+struct StopRequest {
+    /// If set, how long to wait for open connections to finish before closing them forcibly.
+    drain: Option<Duration>,
+
+    /// Signalled once every connection task has exited.
+    done: Option<oneshot::Sender<()>>,
+}
+
+/// Lifecycle bookkeeping for a [`VncServer`].
+struct Lifecycle {
+    state: ServerState,
+
+    /// Addresses to bind to on the next call to [`VncServer::start`].
+    listen: Vec<ListenAddr>,
+
+    /// Addresses the listeners are bound to while the server is running.
+    local_addrs: Vec<ListenAddr>,
+
+    /// One-shot channel used to signal that the server should shut down. Only set while the
+    /// server is running.
+    stop_ch: Option<oneshot::Sender<StopRequest>>,
+
+    /// Channel for handing the server connections accepted elsewhere. Only set while the server
+    /// is running.
+    incoming: Option<mpsc::UnboundedSender<IncomingConn>>,
+}
+
+/// Moves the server to `Stopped` when the accept loop in [`VncServer::start`] exits, including
+/// if the future driving it is dropped.
+struct StoppedGuard<'a>(&'a StdMutex<Lifecycle>);
+
+impl Drop for StoppedGuard<'_> {
+    fn drop(&mut self) {
+        let mut lifecycle = self.0.lock().unwrap();
+        lifecycle.state = ServerState::Stopped;
+        lifecycle.local_addrs.clear();
+        lifecycle.stop_ch = None;
+        lifecycle.incoming = None;
+    }
+}
+
+/// What to do with a new connection when the server already has
+/// [`VncServerConfig::max_connections`] clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Refuse the new connection, telling the client why.
+    #[default]
+    Refuse,
+
+    /// Hold the new connection until an existing client disconnects, refusing it if that takes
+    /// longer than [`VncServerConfig::queue_timeout`].
+    Queue,
+
+    /// Disconnect the client that has been connected the longest.
+    DisconnectOldest,
+
+    /// Disconnect the client that has gone the longest without sending a message.
+    DisconnectIdle,
+}
+
+/// What to do when a client asks for exclusive access by clearing the shared flag in its
+/// ClientInit message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ExclusivePolicy {
+    /// Disconnect every other client, so the new client takes over the display.
+    #[default]
+    DisconnectOthers,
+
+    /// Refuse the new client if any other client is connected.
+    Refuse,
+
+    /// Ignore the request and let the new client share the display.
+    Share,
+}
+
 /// Immutable state
 pub struct VncServerConfig {
-    pub addr: SocketAddr,
+    /// Addresses to accept connections on. Clients record which one they arrived on in
+    /// [`ClientInfo::listener`].
+    pub listen: Vec<ListenAddr>,
     pub version: ProtoVersion,
     pub sec_types: SecurityTypes,
     pub name: String,
+
+    /// Maximum number of clients connected at once, or `None` for no limit.
+    pub max_connections: Option<usize>,
+
+    /// What to do with new connections once `max_connections` is reached.
+    pub overflow_policy: OverflowPolicy,
+
+    /// How long a connection held under [`OverflowPolicy::Queue`] waits for a slot before it is
+    /// refused.
+    pub queue_timeout: Duration,
+
+    /// What to do with clients that ask for exclusive access.
+    pub exclusive_policy: ExclusivePolicy,
+
+    /// Disconnect clients that send no input or update requests for this long. This also bounds
+    /// how long a client may take to complete the handshake.
+    pub idle_timeout: Option<Duration>,
+
+    /// Disconnect clients after they have been connected for this long, regardless of activity.
+    pub max_session_duration: Option<Duration>,
+
+    /// Enable TCP keepalive on client sockets, sending the first probe after the connection has
+    /// been quiet for this long, so that dead peers are detected.
+    pub tcp_keepalive: Option<Duration>,
+
+    /// While a client is waiting for an incremental update and nothing has changed, ask the
+    /// backend for a new frame this often. With `None`, the client waits until the backend
+    /// reports damage through [`VncServer::damage_notifier`], or until it asks again.
+    pub update_poll_interval: Option<Duration>,
+
+    /// Send each client at most this many updates a second. Changes made while a client waits
+    /// out the limit are merged into the update that follows, so clients that ask for a new
+    /// update as soon as one arrives don't cost an encode for every frame the backend draws.
+    pub max_update_rate: Option<u32>,
+}
+
+impl Default for VncServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                5900,
+            ))],
+            version: ProtoVersion::Rfb38,
+            sec_types: SecurityTypes(vec![SecurityType::None]),
+            name: String::new(),
+            max_connections: None,
+            overflow_policy: OverflowPolicy::default(),
+            queue_timeout: Duration::from_secs(30),
+            exclusive_policy: ExclusivePolicy::default(),
+            idle_timeout: None,
+            max_session_duration: None,
+            tcp_keepalive: None,
+            update_poll_interval: Some(Duration::from_millis(50)),
+            max_update_rate: None,
+        }
+    }
 }
 
 /// Mutable state
@@ -63,41 +425,345 @@ pub struct VncServerData {
     pub input_pixel_format: PixelFormat,
 }
 
-pub struct VncServer<S: Server> {
+pub struct VncServer<S: Server, F: ServerFactory<Server = S> = SharedServer<S>> {
     /// VNC startup server configuration
     config: VncServerConfig,
 
     /// VNC runtime mutable state
     data: Mutex<VncServerData>,
 
-    /// The underlying [`Server`] implementation
-    pub server: S,
+    /// Builds the [`Server`] backend for each connection.
+    pub factory: F,
 
-    /// One-shot channel used to signal that the server should shut down.
-    stop_ch: Mutex<Option<oneshot::Sender<()>>>,
+    /// Lifecycle state. This is a synchronous mutex so that it can be updated on drop; it is
+    /// never held across an await point.
+    lifecycle: StdMutex<Lifecycle>,
+
+    /// Open client connections, including those queued waiting for a slot.
+    conns: Connections,
+
+    /// One permit per connection slot, if the number of connections is limited.
+    slots: Option<Arc<Semaphore>>,
+
+    /// Tells connections waiting for an update that the framebuffer changed.
+    damage: DamageNotifier,
 }
 
+/// The backend of a [`VncServer`], which provides the framebuffer contents and receives input.
+///
+/// Callbacks that return a [`BackendError`] close the client's session, unless the error is
+/// [`BackendError::Transient`], in which case the callback is retried a few times first.
+///
+/// The connection lifecycle callbacks ([`client_connected`](Server::client_connected),
+/// [`client_authenticated`](Server::client_authenticated) and
+/// [`client_disconnected`](Server::client_disconnected)) are made through the server's
+/// [`ServerFactory`], never on the backend directly, as a client connects and authenticates
+/// before its backend is built. The default [`SharedServer`] factory forwards them here; a backend
+/// built by any other factory only receives them if that factory forwards them.
 #[async_trait]
 pub trait Server: Sync + Send + 'static {
-    async fn get_framebuffer_update(&self) -> FramebufferUpdate;
-    async fn key_event(&self, _ke: KeyEvent) {}
+    /// Returns the current contents of the framebuffer.
+    ///
+    /// Only `area` is needed, so a backend may render just that part; rectangles outside of it
+    /// are accepted but may not be sent to the client until it asks for them. Raw rectangles are
+    /// compared against what the client already has, so returning unchanged pixels is cheap.
+    ///
+    /// A [`CopyRectEncoding`](crate::encodings::CopyRectEncoding) rectangle says that part of the
+    /// last frame moved, such as a window being dragged, and is applied before the rectangles
+    /// after it.
+    async fn get_framebuffer_update(
+        &self,
+        ctx: &ClientContext,
+        area: Rect,
+    ) -> Result<FramebufferUpdate, BackendError>;
+
+    /// Called for key events from clients with the [`ClientRole::Interactive`] role.
+    async fn key_event(&self, _ctx: &ClientContext, _ke: KeyEvent) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Called for pointer events from clients with the [`ClientRole::Interactive`] role.
+    async fn pointer_event(
+        &self,
+        _ctx: &ClientContext,
+        _pe: PointerEvent,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Called for clipboard text from clients with the [`ClientRole::Interactive`] role.
+    async fn cut_text(&self, _ctx: &ClientContext, _text: String) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Returns the notifier this backend reports damage through, if it has one of its own.
+    /// Connections using the backend then wait on it instead of
+    /// [`VncServer::damage_notifier`], so a backend built for one connection only wakes that one.
+    fn damage_notifier(&self) -> Option<DamageNotifier> {
+        None
+    }
+
+    async fn stop(&self) {}
+
+    /// Called when a client has been admitted, before the RFB handshake.
+    async fn client_connected(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Called once a client has completed the security handshake, to decide who the client is
+    /// and what it may do. By default, every client is anonymous and interactive.
+    async fn client_authenticated(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<Authorization, BackendError> {
+        Ok(Authorization::default())
+    }
+
+    /// Called when a client that was previously reported to
+    /// [`client_connected`](Server::client_connected) goes away.
+    async fn client_disconnected(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+        _reason: DisconnectReason,
+    ) {
+    }
+
+    /// Called when a client changes the pixel format or encodings it wants updates in.
+    async fn client_capabilities_changed(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+/// Builds a [`Server`] backend for each connection.
+///
+/// [`VncServer::new`] shares a single backend between all connections; use
+/// [`VncServer::with_factory`] to give each connection a backend of its own.
+///
+/// The server makes its connection lifecycle callbacks on the factory alone. A factory whose
+/// backends want them, such as [`Server::client_disconnected`] to tear down a session, has to
+/// forward them itself.
+#[async_trait]
+pub trait ServerFactory: Sync + Send + 'static {
+    type Server: Server;
+
+    /// Build the backend for a client that has completed the security handshake.
+    async fn create(
+        &self,
+        ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<Arc<Self::Server>, BackendError>;
+
+    /// Called when the server stops.
     async fn stop(&self) {}
+
+    /// Called when a client has been admitted, before the RFB handshake.
+    async fn client_connected(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Called once a client has completed the security handshake, before its backend is
+    /// built, to decide who the client is and what it may do.
+    async fn client_authenticated(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<Authorization, BackendError> {
+        Ok(Authorization::default())
+    }
+
+    /// Called when a client that was previously reported to
+    /// [`client_connected`](ServerFactory::client_connected) goes away.
+    async fn client_disconnected(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+        _reason: DisconnectReason,
+    ) {
+    }
+}
+
+/// A [`ServerFactory`] that hands the same backend to every connection.
+pub struct SharedServer<S: Server> {
+    server: Arc<S>,
+}
+
+impl<S: Server> SharedServer<S> {
+    pub fn new(server: S) -> Self {
+        Self {
+            server: Arc::new(server),
+        }
+    }
+
+    /// The backend shared by all connections.
+    pub fn server(&self) -> &S {
+        &self.server
+    }
+}
+
+#[async_trait]
+impl<S: Server> ServerFactory for SharedServer<S> {
+    type Server = S;
+
+    async fn create(
+        &self,
+        _ctx: &ClientContext,
+        _info: ClientInfo,
+    ) -> Result<Arc<S>, BackendError> {
+        Ok(self.server.clone())
+    }
+
+    async fn stop(&self) {
+        self.server.stop().await
+    }
+
+    async fn client_connected(
+        &self,
+        ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        self.server.client_connected(ctx, info).await
+    }
+
+    async fn client_authenticated(
+        &self,
+        ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<Authorization, BackendError> {
+        self.server.client_authenticated(ctx, info).await
+    }
+
+    async fn client_disconnected(
+        &self,
+        ctx: &ClientContext,
+        info: ClientInfo,
+        reason: DisconnectReason,
+    ) {
+        self.server.client_disconnected(ctx, info, reason).await
+    }
 }
 
 impl<S: Server> VncServer<S> {
+    /// Create a server whose connections all share `server` as their backend.
     pub fn new(server: S, config: VncServerConfig, data: VncServerData) -> Arc<Self> {
+        Self::with_factory(SharedServer::new(server), config, data)
+    }
+}
+
+impl<S: Server, F: ServerFactory<Server = S>> VncServer<S, F> {
+    /// Create a server that builds a backend for each connection with `factory`.
+    pub fn with_factory(factory: F, config: VncServerConfig, data: VncServerData) -> Arc<Self> {
         assert!(
-            config.sec_types.0.len() > 0,
+            !config.sec_types.0.is_empty(),
             "at least one security type must be defined"
         );
+        let lifecycle = Lifecycle {
+            state: ServerState::NotStarted,
+            listen: config.listen.clone(),
+            local_addrs: Vec::new(),
+            stop_ch: None,
+            incoming: None,
+        };
+        let slots = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
         Arc::new(Self {
-            config: config,
+            config,
             data: Mutex::new(data),
-            server: server,
-            stop_ch: Mutex::new(None),
+            factory,
+            lifecycle: StdMutex::new(lifecycle),
+            conns: Connections::default(),
+            slots,
+            damage: DamageNotifier::new(),
+        })
+    }
+
+    /// Returns the current lifecycle state of the server.
+    pub fn state(&self) -> ServerState {
+        self.lifecycle.lock().unwrap().state
+    }
+
+    /// Returns the first TCP address the server is listening on, if it's running.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let lifecycle = self.lifecycle.lock().unwrap();
+        lifecycle.local_addrs.iter().find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
         })
     }
 
+    /// Returns every address the server is listening on, in the order they were configured. This
+    /// is empty if the server isn't running.
+    pub fn local_addrs(&self) -> Vec<ListenAddr> {
+        self.lifecycle.lock().unwrap().local_addrs.clone()
+    }
+
+    /// Returns handles to every connected client.
+    pub fn clients(&self) -> Vec<ClientHandle> {
+        self.conns.handles()
+    }
+
+    /// Returns a handle to the connected client with the given id.
+    pub fn client(&self, id: ClientId) -> Option<ClientHandle> {
+        self.conns.handle(id)
+    }
+
+    /// Returns the number of connected clients.
+    pub fn connection_count(&self) -> usize {
+        self.conns.count(true)
+    }
+
+    /// Returns the number of connections waiting for a slot under [`OverflowPolicy::Queue`] or
+    /// while another client is being evicted.
+    pub fn queued_connection_count(&self) -> usize {
+        self.conns.count(false)
+    }
+
+    /// Returns a handle the backend can use to report changes to the framebuffer, so that clients
+    /// waiting for an update get one straight away. A backend that reports every change can
+    /// turn off polling with [`VncServerConfig::update_poll_interval`].
+    pub fn damage_notifier(&self) -> DamageNotifier {
+        self.damage.clone()
+    }
+
+    /// Returns a snapshot of the server's metrics: totals across every connection it has had,
+    /// and the metrics of each open connection.
+    pub fn metrics(&self) -> ServerMetrics {
+        self.conns.metrics()
+    }
+
+    /// Change the address the server binds to the next time it is started.
+    ///
+    /// This is only allowed while the server is not running; to move a running server to a new
+    /// address, stop it, set the address and start it again.
+    pub fn set_addr(&self, addr: SocketAddr) -> Result<(), ServerError> {
+        self.set_listen_addrs(vec![ListenAddr::Tcp(addr)])
+    }
+
+    /// Change the addresses the server binds to the next time it is started, as with
+    /// [`VncServer::set_addr`].
+    pub fn set_listen_addrs(&self, addrs: Vec<ListenAddr>) -> Result<(), ServerError> {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        match lifecycle.state {
+            ServerState::Running | ServerState::Stopping => Err(ServerError::AlreadyRunning),
+            ServerState::NotStarted | ServerState::Stopped => {
+                lifecycle.listen = addrs;
+                Ok(())
+            }
+        }
+    }
+
     pub async fn set_pixel_format(&self, pixel_format: PixelFormat) {
         let mut locked = self.data.lock().await;
         locked.input_pixel_format = pixel_format;
@@ -109,23 +775,28 @@ impl<S: Server> VncServer<S> {
         locked.height = height;
     }
 
-    async fn rfb_handshake(
-        &self,
-        s: &mut TcpStream,
-        addr: SocketAddr,
-    ) -> Result<(), HandshakeError> {
-        // ProtocolVersion handshake
-        info!("Tx [{:?}]: ProtoVersion={:?}", addr, self.config.version);
+    /// Send the server's protocol version and read the client's.
+    async fn rfb_versions(&self, s: &mut ClientStream) -> Result<ProtoVersion, ProtocolError> {
+        info!("Tx: ProtoVersion={:?}", self.config.version);
         self.config.version.write_to(s).await?;
         let client_version = ProtoVersion::read_from(s).await?;
-        info!("Rx [{:?}]: ClientVersion={:?}", addr, client_version);
+        info!("Rx: ClientVersion={:?}", client_version);
+        Ok(client_version)
+    }
 
+    /// Complete the handshake, once protocol versions have been exchanged.
+    #[instrument(name = "handshake", level = "debug", skip_all)]
+    async fn rfb_handshake(
+        &self,
+        s: &mut ClientStream,
+        client_version: ProtoVersion,
+    ) -> Result<(ProtoVersion, SecurityType), HandshakeError> {
         if client_version < self.config.version {
-            let err_str = format!(
-                "[{:?}] unsupported client version={:?} (server version: {:?})",
-                addr, client_version, self.config.version
+            error!(
+                client = ?client_version,
+                server = ?self.config.version,
+                "unsupported client version"
             );
-            error!("{}", err_str);
             return Err(HandshakeError::IncompatibleVersions {
                 client: client_version,
                 server: self.config.version,
@@ -134,16 +805,15 @@ impl<S: Server> VncServer<S> {
 
         // Security Handshake
         let supported_types = self.config.sec_types.clone();
-        info!("Tx [{:?}]: SecurityTypes={:?}", addr, supported_types);
+        info!("Tx: SecurityTypes={:?}", supported_types);
         supported_types.write_to(s).await?;
         let client_choice = SecurityType::read_from(s).await?;
-        info!("Rx [{:?}]: SecurityType Choice={:?}", addr, client_choice);
+        info!("Rx: SecurityType Choice={:?}", client_choice);
         if !self.config.sec_types.0.contains(&client_choice) {
-            info!("Tx [{:?}]: SecurityResult=Failure", addr);
+            info!("Tx: SecurityResult=Failure");
             let failure = SecurityResult::Failure("unsupported security type".to_string());
             failure.write_to(s).await?;
-            let err_str = format!("invalid security choice={:?}", client_choice);
-            error!("{}", err_str);
+            error!(choice = ?client_choice, "invalid security choice");
             return Err(HandshakeError::IncompatibleSecurityTypes {
                 choice: client_choice,
                 offer: self.config.sec_types.clone(),
@@ -154,170 +824,1884 @@ impl<S: Server> VncServer<S> {
         info!("Tx: SecurityResult=Success");
         res.write_to(s).await?;
 
-        Ok(())
+        Ok((client_version, client_choice))
     }
 
-    async fn rfb_initialization(
+    /// Refuse a connection after the protocol version handshake, telling the client why.
+    #[instrument(name = "refuse", level = "debug", skip_all)]
+    async fn rfb_refuse(
         &self,
-        s: &mut TcpStream,
-        addr: SocketAddr,
+        s: &mut ClientStream,
+        reason: &DisconnectReason,
     ) -> Result<(), ProtocolError> {
-        let client_init = ClientInit::read_from(s).await?;
-        info!("Rx [{:?}]: ClientInit={:?}", addr, client_init);
-        // TODO: decide what to do in exclusive case
-        match client_init.shared {
-            true => {}
-            false => {}
-        }
+        self.rfb_versions(s).await?;
 
-        let data = self.data.lock().await;
-        let server_init = ServerInit::new(
-            data.width,
-            data.height,
-            self.config.name.clone(),
-            data.input_pixel_format.clone(),
-        );
-        info!("Tx [{:?}]: ServerInit={:#?}", addr, server_init);
-        server_init.write_to(s).await?;
+        info!("Tx: ConnectionFailure={}", reason);
+        ConnectionFailure(reason.to_string()).write_to(s).await?;
 
         Ok(())
     }
 
-    async fn handle_conn(&self, s: &mut TcpStream, addr: SocketAddr, mut close_ch: Shared<oneshot::Receiver<()>>) {
-        info!("[{:?}] new connection", addr);
+    /// Wait for a connection slot according to the server's [`OverflowPolicy`].
+    ///
+    /// Returns `Ok(None)` if the number of connections isn't limited.
+    async fn acquire_slot(
+        &self,
+        close_ch: &mut watch::Receiver<Option<DisconnectReason>>,
+    ) -> Result<Option<OwnedSemaphorePermit>, DisconnectReason> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
 
-        if let Err(e) = self.rfb_handshake(s, addr).await {
-            error!("[{:?}] could not complete handshake: {:?}", addr, e);
-            return;
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(Some(permit));
         }
 
-        if let Err(e) = self.rfb_initialization(s, addr).await {
-            error!("[{:?}] could not complete handshake: {:?}", addr, e);
-            return;
+        let policy = self.config.overflow_policy;
+        match policy {
+            OverflowPolicy::Refuse => return Err(DisconnectReason::ConnectionLimit),
+            OverflowPolicy::Queue => {}
+            OverflowPolicy::DisconnectOldest | OverflowPolicy::DisconnectIdle => {
+                if !self.conns.evict(policy) {
+                    // Every slot is held by a connection still waiting to be admitted.
+                    return Err(DisconnectReason::ConnectionLimit);
+                }
+            }
         }
 
-        let data = self.data.lock().await;
-        let mut output_pixel_format = data.input_pixel_format.clone();
-        drop(data);
+        let deadline = match policy {
+            OverflowPolicy::Queue => Some(TokioInstant::now() + self.config.queue_timeout),
+            _ => None,
+        };
+        select! {
+            biased;
 
-        loop {
-            let req = select! {
-                // Poll in the order written so we check for close first
-                biased;
+            reason = wait_for_close(close_ch) => Err(reason),
+
+            permit = slots.clone().acquire_owned() => Ok(Some(permit.unwrap())),
+
+            _ = sleep_until_opt(deadline) => {
+                info!("no connection slot freed up in time");
+                Err(DisconnectReason::ConnectionLimit)
+            }
+        }
+    }
 
-                _ = &mut close_ch => {
-                    info!("[{:?}] server stopping, closing connection with peer", addr);
-                    let _ = s.shutdown().await;
-                    return;
+    /// Run a connection from admission to close.
+    async fn run_conn(
+        &self,
+        s: &mut ClientStream,
+        client: &Client,
+        mut channels: ClientChannels,
+        wait_for_peer: bool,
+    ) -> DisconnectReason {
+        let _permit = match self.acquire_slot(&mut channels.close_rx).await {
+            Ok(permit) => permit,
+            Err(reason) => {
+                if reason == DisconnectReason::ConnectionLimit {
+                    if let Err(e) = self.rfb_refuse(s, &reason).await {
+                        error!("could not refuse connection: {:?}", e);
+                    }
                 }
+                return reason;
+            }
+        };
+        client.activate();
 
-                req = ClientMessage::read_from(s) => req,
-            };
+        let reason = match self
+            .factory
+            .client_connected(&client.context(), client.info())
+            .await
+        {
+            Ok(()) => {
+                self.handle_conn(s, client, &mut channels, wait_for_peer)
+                    .await
+            }
+            Err(e) => {
+                error!("backend refused connection: {}", e);
+                e.into()
+            }
+        };
+        self.factory
+            .client_disconnected(&client.context(), client.info(), reason.clone())
+            .await;
+        reason
+    }
 
-            match req {
-                Ok(client_msg) => match client_msg {
-                    ClientMessage::SetPixelFormat(pf) => {
-                        debug!("Rx [{:?}]: SetPixelFormat={:#?}", addr, pf);
+    #[instrument(name = "init", level = "debug", skip_all)]
+    async fn rfb_initialization(
+        &self,
+        s: &mut ClientStream,
+        client: &Client,
+    ) -> Result<(), DisconnectReason> {
+        let handshake_failed = |e: ProtocolError| {
+            error!("could not complete handshake: {:?}", e);
+            DisconnectReason::HandshakeFailed(e.to_string())
+        };
 
-                        // TODO: invalid pixel formats?
-                        output_pixel_format = pf;
-                    }
-                    ClientMessage::SetEncodings(e) => {
-                        debug!("Rx [{:?}]: SetEncodings={:?}", addr, e);
-                    }
-                    ClientMessage::FramebufferUpdateRequest(f) => {
-                        debug!("Rx [{:?}]: FramebufferUpdateRequest={:?}", addr, f);
-
-                        let mut fbu = self.server.get_framebuffer_update().await;
-
-                        let data = self.data.lock().await;
-
-                        // We only need to change pixel formats if the client requested a different
-                        // one than what's specified in the input.
-                        //
-                        // For now, we only support transformations between 4-byte RGB formats, so
-                        // if the requested format isn't one of those, we'll just leave the pixels
-                        // as is.
-                        if data.input_pixel_format != output_pixel_format
-                            && data.input_pixel_format.is_rgb_888()
-                            && output_pixel_format.is_rgb_888()
-                        {
-                            debug!(
-                                "transforming: input={:#?}, output={:#?}",
-                                data.input_pixel_format, output_pixel_format
-                            );
-                            fbu = fbu.transform(&data.input_pixel_format, &output_pixel_format);
-                        } else if !(data.input_pixel_format.is_rgb_888()
-                            && output_pixel_format.is_rgb_888())
-                        {
-                            debug!("cannot transform between pixel formats (not rgb888): input.is_rgb_888()={}, output.is_rgb_888()={}", data.input_pixel_format.is_rgb_888(), output_pixel_format.is_rgb_888());
-                        } else {
-                            debug!("no input transformation needed");
-                        }
+        let client_init = ClientInit::read_from(s).await.map_err(handshake_failed)?;
+        info!("Rx: ClientInit={:?}", client_init);
+        client.state.lock().unwrap().shared = Some(client_init.shared);
 
-                        if let Err(e) = fbu.write_to(s).await {
-                            error!(
-                                "[{:?}] could not write FramebufferUpdateRequest: {:?}",
-                                addr, e
-                            );
-                            return;
-                        }
-                        debug!("Tx [{:?}]: FramebufferUpdate", addr);
-                    }
-                    ClientMessage::KeyEvent(ke) => {
-                        trace!("Rx [{:?}]: KeyEvent={:?}", addr, ke);
-                        self.server.key_event(ke).await;
+        if !client_init.shared {
+            match self.config.exclusive_policy {
+                ExclusivePolicy::DisconnectOthers => {
+                    let n = self
+                        .conns
+                        .close_others(client.id(), DisconnectReason::Replaced);
+                    if n > 0 {
+                        info!(
+                            disconnected = n,
+                            "client asked for exclusive access, disconnected other clients"
+                        );
                     }
-                    ClientMessage::PointerEvent(pe) => {
-                        trace!("Rx [{:?}: PointerEvent={:?}", addr, pe);
-                    }
-                    ClientMessage::ClientCutText(t) => {
-                        trace!("Rx [{:?}: ClientCutText={:?}", addr, t);
+                }
+                ExclusivePolicy::Refuse => {
+                    if self.conns.count(true) > 1 {
+                        info!("client asked for exclusive access while others are connected");
+                        return Err(DisconnectReason::ExclusiveAccessDenied);
                     }
-                },
-                Err(e) => {
-                    error!("[{:?}] error reading client message: {}", addr, e);
-                    return;
                 }
+                ExclusivePolicy::Share => {}
             }
         }
-    }
-
-    /// Start listening for incoming connections.
-    pub async fn start(self: &Arc<Self>) -> io::Result<()> {
-        let listener = TcpListener::bind(self.config.addr).await?;
 
-        // Create a channel to signal the server to stop.
-        let (close_tx, close_rx) = oneshot::channel();
-        assert!(self.stop_ch.lock().await.replace(close_tx).is_none(), "server already started");
-        let mut close_rx = close_rx.shared();
+        let data = self.data.lock().await;
+        let server_init = ServerInit::new(
+            data.width,
+            data.height,
+            self.config.name.clone(),
+            data.input_pixel_format.clone(),
+        );
+        info!("Tx: ServerInit={:#?}", server_init);
+        server_init.write_to(s).await.map_err(handshake_failed)?;
+
+        Ok(())
+    }
+
+    /// Ask the backend for `area` of the current frame, and fold what changed into the client's
+    /// damage.
+    #[instrument(name = "poll", level = "debug", skip_all)]
+    async fn poll_backend(
+        &self,
+        backend: &S,
+        client: &Client,
+        updates: &mut UpdateState,
+        area: Rect,
+    ) -> Result<(), DisconnectReason> {
+        updates.last_poll = Instant::now();
+        let ctx = client.context();
+        let fbu = retry_transient(|| backend.get_framebuffer_update(&ctx, area))
+            .instrument(debug_span!("backend"))
+            .await?;
+
+        let data = self.data.lock().await;
+        updates.resize(data.width, data.height, &data.input_pixel_format);
+        drop(data);
+        debug_span!("diff").in_scope(|| updates.apply(fbu));
+        Ok(())
+    }
+
+    /// Bring the client's view of the framebuffer up to date, and send it an update if it is
+    /// waiting for one and something changed. With `force`, send an update even if the client
+    /// didn't ask for one.
+    async fn refresh_client(
+        &self,
+        s: &mut dyn WriteStream,
+        backend: &S,
+        client: &Client,
+        updates: &mut UpdateState,
+        force: bool,
+    ) -> Result<(), DisconnectReason> {
+        let area = updates.requested_area();
+        self.refresh_area(s, backend, client, updates, area, force)
+            .await
+    }
+
+    /// Like [`refresh_client`](Self::refresh_client), but only asking the backend for `area`,
+    /// such as a part of the framebuffer it reported as damaged.
+    async fn refresh_area(
+        &self,
+        s: &mut dyn WriteStream,
+        backend: &S,
+        client: &Client,
+        updates: &mut UpdateState,
+        area: Rect,
+        force: bool,
+    ) -> Result<(), DisconnectReason> {
+        if !force && (updates.awaiting_fence() || self.cooldown_end(updates).is_some()) {
+            trace!("update rate limited");
+            updates.deferred = true;
+            return Ok(());
+        }
+        updates.deferred = false;
+
+        let started = Instant::now();
+        self.poll_backend(backend, client, updates, area).await?;
+        self.send_update(s, client, updates, force, started).await
+    }
+
+    /// Send the client the parts of the framebuffer it is owed, in the pixel format it asked
+    /// for. `started` is when work on the update began, for the encode time metric.
+    #[instrument(name = "update", level = "debug", skip_all)]
+    async fn send_update(
+        &self,
+        s: &mut dyn WriteStream,
+        client: &Client,
+        updates: &mut UpdateState,
+        force: bool,
+        started: Instant,
+    ) -> Result<(), DisconnectReason> {
+        let Some((mut fbu, requested_at)) = updates.take_update(force) else {
+            trace!("nothing to send");
+            return Ok(());
+        };
+        let output_pixel_format = &updates.output_format;
+
+        let data = self.data.lock().await;
+
+        // We only need to change pixel formats if the client requested a different
+        // one than what's specified in the input.
+        //
+        // For now, we only support transformations between 4-byte RGB formats, so
+        // if the requested format isn't one of those, we'll just leave the pixels
+        // as is.
+        if data.input_pixel_format != *output_pixel_format
+            && data.input_pixel_format.is_rgb_888()
+            && output_pixel_format.is_rgb_888()
+        {
+            debug!(
+                "transforming: input={:#?}, output={:#?}",
+                data.input_pixel_format, output_pixel_format
+            );
+            fbu = debug_span!("transform")
+                .in_scope(|| fbu.transform(&data.input_pixel_format, output_pixel_format));
+        } else if !(data.input_pixel_format.is_rgb_888() && output_pixel_format.is_rgb_888()) {
+            debug!("cannot transform between pixel formats (not rgb888): input.is_rgb_888()={}, output.is_rgb_888()={}", data.input_pixel_format.is_rgb_888(), output_pixel_format.is_rgb_888());
+        } else {
+            debug!("no input transformation needed");
+        }
+        drop(data);
+
+        let accepted = client.state.lock().unwrap().encodings.clone();
+        let format = &updates.output_format;
+        fbu = debug_span!("encode").in_scope(|| updates.link.encode(fbu, &accepted, format));
+        let encode_time = started.elapsed();
+
+        let encodings = fbu
+            .rectangles()
+            .iter()
+            .map(|r| r.encoding_type())
+            .collect::<Vec<_>>();
+        debug!(rects = encodings.len(), "Tx: FramebufferUpdate");
+        let write_started = Instant::now();
+        let bytes_before = client.metrics.bytes_out();
+        fbu.write_to(s).instrument(debug_span!("write")).await?;
+        let bytes = client.metrics.bytes_out() - bytes_before;
+        updates.link.sent(write_started, bytes as usize);
+        if updates.continuous().is_some() && updates.fence_supported {
+            self.send_fence(s, updates).await?;
+        }
+        client
+            .metrics
+            .record_update(encodings, encode_time, requested_at.elapsed());
+
+        Ok(())
+    }
+
+    /// Carry out a request made through the client's [`ClientHandle`].
+    async fn handle_command(
+        &self,
+        s: &mut dyn WriteStream,
+        backend: &S,
+        client: &Client,
+        cmd: ClientCommand,
+        updates: &mut UpdateState,
+    ) -> Result<(), DisconnectReason> {
+        match cmd {
+            ClientCommand::Bell => {
+                debug!("Tx: Bell");
+                Ok(ServerMessage::Bell.write_to(s).await?)
+            }
+            ClientCommand::CutText(text) => {
+                debug!("Tx: ServerCutText={:?}", text);
+                Ok(ServerMessage::ServerCutText(CutText::new(text))
+                    .write_to(s)
+                    .await?)
+            }
+            ClientCommand::Refresh => {
+                debug!("refresh requested");
+                updates.invalidate();
+                self.refresh_client(s, backend, client, updates, true).await
+            }
+            ClientCommand::Drain => {
+                // Answer the request the client is waiting on with whatever changed, regardless
+                // of the update rate limit, as there won't be another chance.
+                if updates.is_pending() {
+                    let area = updates.requested_area();
+                    let started = Instant::now();
+                    self.poll_backend(backend, client, updates, area).await?;
+                    self.send_update(s, client, updates, false, started).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// If the client was sent an update too recently to be sent another, when it may be.
+    ///
+    /// Clients getting continuous updates also wait for the last update to have crossed their
+    /// link, at the bandwidth measured for it, as they no longer pace updates themselves.
+    fn cooldown_end(&self, updates: &UpdateState) -> Option<Instant> {
+        let rate_limited = self
+            .config
+            .max_update_rate
+            .filter(|&r| r > 0)
+            .and_then(|rate| Some(updates.last_sent()? + Duration::from_secs(1) / rate));
+        let drained = updates.continuous().and_then(|_| updates.link.drained_at());
+        let end = rate_limited.into_iter().chain(drained).max()?;
+        (end > Instant::now()).then_some(end)
+    }
+
+    /// When to next poll the backend on behalf of a client waiting for an update, if at all:
+    /// either to look for changes, or because an update was held back by the rate limit.
+    fn next_poll(&self, updates: &UpdateState) -> Option<TokioInstant> {
+        // A client being waited on to answer a fence is sent its next update once it does.
+        if !updates.is_pending() || updates.awaiting_fence() {
+            return None;
+        }
+        let poll = self
+            .config
+            .update_poll_interval
+            .map(|interval| updates.last_poll + interval);
+        let deferred = updates.deferred.then(Instant::now);
+        let at = poll.into_iter().chain(deferred).min()?;
+        let at = self.cooldown_end(updates).map_or(at, |end| at.max(end));
+        Some(TokioInstant::from_std(at))
+    }
+
+    /// Ask the client to answer a fence, which it does once it has handled everything sent
+    /// before it.
+    async fn send_fence(
+        &self,
+        s: &mut dyn WriteStream,
+        updates: &mut UpdateState,
+    ) -> Result<(), DisconnectReason> {
+        let fence = Fence {
+            flags: FenceFlags::REQUEST,
+            payload: Vec::new(),
+        };
+        debug!("Tx: Fence={:?}", fence);
+        ServerMessage::Fence(fence).write_to(s).await?;
+        updates.fence_sent();
+        Ok(())
+    }
+
+    /// Act on a message from the client.
+    async fn handle_message(
+        &self,
+        s: &mut dyn WriteStream,
+        backend: &S,
+        client: &Client,
+        ctx: &ClientContext,
+        msg: ClientMessage,
+        updates: &mut UpdateState,
+    ) -> Result<(), DisconnectReason> {
+        match msg {
+            ClientMessage::SetPixelFormat(pf) => {
+                debug!("Rx: SetPixelFormat={:#?}", pf);
+
+                // TODO: invalid pixel formats?
+                client.state.lock().unwrap().pixel_format = Some(pf.clone());
+                updates.output_format = pf;
+                if let Err(e) = backend
+                    .client_capabilities_changed(ctx, client.info())
+                    .await
+                {
+                    error!("backend rejected pixel format: {}", e);
+                    return Err(e.into());
+                }
+            }
+            ClientMessage::SetEncodings(e) => {
+                debug!("Rx: SetEncodings={:?}", e);
+                updates.copy_rect = e.contains(&EncodingType::CopyRect);
+
+                // Clients learn that the server supports these extensions by being sent one of
+                // their messages, the first time they ask for them.
+                if e.contains(&EncodingType::ContinuousUpdatesPseudo)
+                    && !updates.continuous_supported
+                {
+                    updates.continuous_supported = true;
+                    debug!("Tx: EndOfContinuousUpdates");
+                    ServerMessage::EndOfContinuousUpdates.write_to(s).await?;
+                }
+                if e.contains(&EncodingType::FencePseudo) && !updates.fence_supported {
+                    updates.fence_supported = true;
+                    self.send_fence(s, updates).await?;
+                }
+                client.state.lock().unwrap().encodings = e;
+                if let Err(e) = backend
+                    .client_capabilities_changed(ctx, client.info())
+                    .await
+                {
+                    error!("backend rejected encodings: {}", e);
+                    return Err(e.into());
+                }
+            }
+            ClientMessage::FramebufferUpdateRequest(f) => {
+                debug!("Rx: FramebufferUpdateRequest={:?}", f);
+                let now = Instant::now();
+                updates.request(f.incremental(), f.rect(), now);
+                updates.link.requested(now);
+                client.state.lock().unwrap().link = updates.link.stats();
+
+                if let Err(e) = self
+                    .refresh_client(s, backend, client, updates, false)
+                    .await
+                {
+                    error!("could not send FramebufferUpdate: {}", e);
+                    return Err(e);
+                }
+            }
+            ClientMessage::KeyEvent(ke) => {
+                trace!("Rx: KeyEvent={:?}", ke);
+                client.metrics.record_key_event();
+                if ctx.role == ClientRole::ViewOnly {
+                    return Ok(());
+                }
+                if let Err(e) = retry_transient(|| backend.key_event(ctx, ke)).await {
+                    error!("backend could not handle key event: {}", e);
+                    return Err(e.into());
+                }
+            }
+            ClientMessage::PointerEvent(pe) => {
+                trace!("Rx: PointerEvent={:?}", pe);
+                client.metrics.record_pointer_event();
+                if ctx.role == ClientRole::ViewOnly {
+                    return Ok(());
+                }
+                if let Err(e) = retry_transient(|| backend.pointer_event(ctx, pe)).await {
+                    error!("backend could not handle pointer event: {}", e);
+                    return Err(e.into());
+                }
+            }
+            ClientMessage::ClientCutText(t) => {
+                trace!("Rx: ClientCutText={:?}", t);
+                if ctx.role == ClientRole::ViewOnly {
+                    return Ok(());
+                }
+                if let Err(e) = retry_transient(|| backend.cut_text(ctx, t.clone())).await {
+                    error!("backend could not handle cut text: {}", e);
+                    return Err(e.into());
+                }
+            }
+            ClientMessage::EnableContinuousUpdates(c) => {
+                debug!("Rx: EnableContinuousUpdates={:?}", c);
+                if !c.enable() {
+                    updates.set_continuous(None, Instant::now());
+                    debug!("Tx: EndOfContinuousUpdates");
+                    ServerMessage::EndOfContinuousUpdates.write_to(s).await?;
+                    return Ok(());
+                }
+
+                updates.set_continuous(Some(c.rect()), Instant::now());
+                if let Err(e) = self
+                    .refresh_client(s, backend, client, updates, false)
+                    .await
+                {
+                    error!("could not send FramebufferUpdate: {}", e);
+                    return Err(e);
+                }
+            }
+            ClientMessage::Fence(f) => {
+                debug!("Rx: Fence={:?}", f);
+                if f.flags.contains(FenceFlags::REQUEST) {
+                    // Messages are handled one at a time and in order, so everything before the
+                    // fence already has been, and nothing after it will be until it's answered.
+                    // The answer only carries the flags we know how to honour.
+                    let reply = Fence {
+                        flags: f.flags
+                            & (FenceFlags::BLOCK_BEFORE
+                                | FenceFlags::BLOCK_AFTER
+                                | FenceFlags::SYNC_NEXT),
+                        payload: f.payload,
+                    };
+                    if reply.flags.contains(FenceFlags::SYNC_NEXT) {
+                        updates.sync_fence = Some(reply);
+                    } else {
+                        debug!("Tx: Fence={:?}", reply);
+                        ServerMessage::Fence(reply).write_to(s).await?;
+                    }
+                    return Ok(());
+                }
+
+                // The client has caught up with an update we sent, which also times the link.
+                updates.fence_answered();
+                updates.link.requested(Instant::now());
+                client.state.lock().unwrap().link = updates.link.stats();
+                if updates.deferred {
+                    if let Err(e) = self
+                        .refresh_client(s, backend, client, updates, false)
+                        .await
+                    {
+                        error!("could not send FramebufferUpdate: {}", e);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serve a client from the handshake until the connection closes.
+    ///
+    /// The handshake must complete within the idle timeout. For a connection that sits waiting
+    /// for a peer before the handshake can start, such as a registration with a repeater,
+    /// `wait_for_peer` starts that clock only once the client has answered our protocol version.
+    async fn handle_conn(
+        &self,
+        s: &mut ClientStream,
+        client: &Client,
+        channels: &mut ClientChannels,
+        wait_for_peer: bool,
+    ) -> DisconnectReason {
+        info!("new connection");
+        let connected_at = TokioInstant::now();
+
+        let mut client_version = None;
+        if wait_for_peer {
+            match self.rfb_versions(s).await {
+                Ok(version) => client_version = Some(version),
+                Err(e) => {
+                    error!("could not complete handshake: {:?}", e);
+                    return DisconnectReason::HandshakeFailed(e.to_string());
+                }
+            }
+        }
+
+        let setup = async {
+            let handshake = async {
+                let client_version = match client_version {
+                    Some(version) => version,
+                    None => self.rfb_versions(s).await?,
+                };
+                self.rfb_handshake(s, client_version).await
+            };
+            let (version, security_type) = match handshake.await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("could not complete handshake: {:?}", e);
+                    return Err(DisconnectReason::HandshakeFailed(e.to_string()));
+                }
+            };
+            Span::current().record("version", field::debug(version));
+            {
+                let mut state = client.state.lock().unwrap();
+                state.version = Some(version);
+                state.security_type = Some(security_type);
+            }
+            let authorization = match self
+                .factory
+                .client_authenticated(&client.context(), client.info())
+                .await
+            {
+                Ok(authorization) => authorization,
+                Err(e) => {
+                    error!("backend rejected client: {}", e);
+                    return Err(e.into());
+                }
+            };
+            info!(
+                identity = ?authorization.identity,
+                role = ?authorization.role,
+                "client authorized"
+            );
+            client.state.lock().unwrap().authorization = authorization;
+
+            let backend = match self.factory.create(&client.context(), client.info()).await {
+                Ok(backend) => backend,
+                Err(e) => {
+                    error!("could not create backend: {}", e);
+                    return Err(e.into());
+                }
+            };
+
+            self.rfb_initialization(s, client).await?;
+
+            Ok(backend)
+        };
+        let setup = match self.config.idle_timeout {
+            Some(t) => tokio::time::timeout(t, setup)
+                .await
+                .unwrap_or(Err(DisconnectReason::IdleTimeout)),
+            None => setup.await,
+        };
+        let backend = match setup {
+            Ok(backend) => backend,
+            Err(reason) => return reason,
+        };
+
+        let ctx = client.context();
+        let session_deadline = self.config.max_session_duration.map(|d| connected_at + d);
+        let mut last_activity = TokioInstant::now();
+
+        let data = self.data.lock().await;
+        let mut updates = UpdateState::new(data.input_pixel_format.clone());
+        updates.resize(data.width, data.height, &data.input_pixel_format);
+        drop(data);
+        client.state.lock().unwrap().pixel_format = Some(updates.output_format.clone());
+        // A backend with a notifier of its own only wakes the connections using it.
+        let mut damage = backend
+            .damage_notifier()
+            .unwrap_or_else(|| self.damage.clone())
+            .subscribe();
+
+        // Read client messages from their own half of the stream, so that waiting for the next
+        // message can be interrupted (by a request from the client's handle, say) without losing
+        // a partially read message.
+        let (rd, mut wr) = tokio::io::split(s);
+        let msgs = stream::unfold(rd, |mut rd| async move {
+            let msg = ClientMessage::read_from(&mut rd).await;
+            Some((msg, rd))
+        });
+        tokio::pin!(msgs);
 
         loop {
-            let (mut client_sock, client_addr) = select! {
+            // Updates are written outside of this select, so a stop request never interrupts a
+            // message that is already being handled: in-flight updates always finish.
+            let req = select! {
                 // Poll in the order written so we check for close first
                 biased;
 
-                _ = &mut close_rx => {
-                    info!("server stopping");
-                    self.server.stop().await;
-                    return Ok(());
+                reason = wait_for_close(&mut channels.close_rx) => {
+                    info!("{}, closing connection with peer", reason);
+                    let _ = wr.shutdown().await;
+                    return reason;
+                }
+
+                _ = sleep_until_opt(session_deadline) => {
+                    info!("session expired, closing connection with peer");
+                    let _ = wr.shutdown().await;
+                    return DisconnectReason::SessionExpired;
+                }
+
+                _ = sleep_until_opt(self.config.idle_timeout.map(|t| last_activity + t)) => {
+                    info!("idle timeout, closing connection with peer");
+                    let _ = wr.shutdown().await;
+                    return DisconnectReason::IdleTimeout;
+                }
+
+                Some(cmd) = channels.cmd_rx.recv() => {
+                    let drain = matches!(cmd, ClientCommand::Drain);
+                    if let Err(e) = self
+                        .handle_command(&mut wr, &backend, client, cmd, &mut updates)
+                        .await
+                    {
+                        error!("could not carry out client request: {}", e);
+                        return e;
+                    }
+                    if drain {
+                        info!("server stopping, closing connection with peer");
+                        let _ = wr.shutdown().await;
+                        return DisconnectReason::ServerShutdown;
+                    }
+                    continue;
                 }
 
-                conn = listener.accept() => conn?,
+                damaged = damage.recv() => {
+                    trace!(?damaged, "damage reported");
+                    // Only what was damaged needs polling, unless notifications were missed, in
+                    // which case the whole framebuffer may have changed. Damage outside of what
+                    // the client asked for is picked up when it asks for it.
+                    let requested = updates.requested_area();
+                    let area = match damaged {
+                        Some(region) => region.bounds().intersection(&requested),
+                        None => Some(requested),
+                    };
+                    if let Some(area) = area.filter(|_| updates.is_pending()) {
+                        if let Err(e) = self
+                            .refresh_area(&mut wr, &backend, client, &mut updates, area, false)
+                            .await
+                        {
+                            error!("could not send FramebufferUpdate: {}", e);
+                            return e;
+                        }
+                    }
+                    continue;
+                }
+
+                // While the client waits for an update, poll the backend for changes.
+                _ = sleep_until_opt(self.next_poll(&updates)) => {
+                    if let Err(e) = self
+                        .refresh_client(&mut wr, &backend, client, &mut updates, false)
+                        .await
+                    {
+                        error!("could not send FramebufferUpdate: {}", e);
+                        return e;
+                    }
+                    continue;
+                }
+
+                Some(req) = msgs.next() => req,
+            };
+
+            let client_msg = match req {
+                Ok(client_msg) => client_msg,
+                Err(e) => {
+                    error!("error reading client message: {}", e);
+                    return e.into();
+                }
             };
+            if matches!(
+                client_msg,
+                ClientMessage::FramebufferUpdateRequest(_)
+                    | ClientMessage::KeyEvent(_)
+                    | ClientMessage::PointerEvent(_)
+                    | ClientMessage::ClientCutText(_)
+                    | ClientMessage::EnableContinuousUpdates(_)
+            ) {
+                last_activity = TokioInstant::now();
+                client.touch();
+            }
+
+            // A fence asking to be synchronised with the next message is answered once that
+            // message has been handled.
+            let sync_fence = updates.sync_fence.take();
+            let span = debug_span!("message", kind = client_msg.name());
+            if let Err(reason) = self
+                .handle_message(&mut wr, &backend, client, &ctx, client_msg, &mut updates)
+                .instrument(span)
+                .await
+            {
+                return reason;
+            }
+            if let Some(reply) = sync_fence {
+                debug!("Tx: Fence={:?}", reply);
+                if let Err(e) = ServerMessage::Fence(reply).write_to(&mut wr).await {
+                    error!("could not answer fence: {}", e);
+                    return e.into();
+                }
+            }
+        }
+    }
+
+    /// Start listening for incoming connections.
+    ///
+    /// This future runs until the server is stopped with [`VncServer::stop`] or
+    /// [`VncServer::stop_gracefully`]. Once it has returned, the server may be started again.
+    pub async fn start(self: &Arc<Self>) -> Result<(), ServerError> {
+        self.run(true, None).await
+    }
+
+    /// Run the server without a listener of its own, so that it only serves connections handed
+    /// to it with [`VncServer::serve`].
+    ///
+    /// Like [`VncServer::start`], this future runs until the server is stopped.
+    pub async fn start_without_listener(self: &Arc<Self>) -> Result<(), ServerError> {
+        self.run(false, None).await
+    }
+
+    /// Serve a client connection accepted elsewhere, such as a WebSocket tunnel. The server must
+    /// be running.
+    pub fn serve(
+        &self,
+        stream: impl RfbStream + 'static,
+        addr: impl Into<PeerAddr>,
+    ) -> Result<(), ServerError> {
+        self.serve_conn(Box::new(stream), addr.into(), false)
+    }
+
+    /// Hand a connection to the running server, with `wait_for_peer` as for
+    /// [`VncServer::handle_conn`].
+    pub(crate) fn serve_conn(
+        &self,
+        stream: Box<dyn RfbStream>,
+        addr: PeerAddr,
+        wait_for_peer: bool,
+    ) -> Result<(), ServerError> {
+        let lifecycle = self.lifecycle.lock().unwrap();
+        match (&lifecycle.state, &lifecycle.incoming) {
+            (ServerState::Running, Some(incoming)) => incoming
+                .send((stream, addr, wait_for_peer))
+                .map_err(|_| ServerError::NotRunning),
+            _ => Err(ServerError::NotRunning),
+        }
+    }
+
+    /// Run the server, with or without its own listener, signalling `ready` once it can accept
+    /// connections.
+    pub(crate) async fn run(
+        self: &Arc<Self>,
+        listen: bool,
+        ready: Option<oneshot::Sender<()>>,
+    ) -> Result<(), ServerError> {
+        let addrs = {
+            let mut lifecycle = self.lifecycle.lock().unwrap();
+            match lifecycle.state {
+                ServerState::Running | ServerState::Stopping => {
+                    return Err(ServerError::AlreadyRunning);
+                }
+                ServerState::NotStarted | ServerState::Stopped => {}
+            }
+
+            // Claim the server before binding, so a concurrent start fails rather than racing
+            // for the listener.
+            lifecycle.state = ServerState::Running;
+            lifecycle.listen.clone()
+        };
+        let stopped = StoppedGuard(&self.lifecycle);
+
+        let listeners = match listen {
+            true => addrs
+                .iter()
+                .map(Listener::bind)
+                .collect::<Result<Vec<_>, _>>()?,
+            false => Vec::new(),
+        };
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Create a channel to signal the server to stop, and one for connections accepted
+        // elsewhere.
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        {
+            let mut lifecycle = self.lifecycle.lock().unwrap();
+            lifecycle.local_addrs = local_addrs.clone();
+            lifecycle.stop_ch = Some(stop_tx);
+            lifecycle.incoming = Some(incoming_tx);
+        }
+        match local_addrs.is_empty() {
+            false => info!("server listening on {:?}", local_addrs),
+            true => info!("server running without a listener"),
+        }
+        if let Some(ready) = ready {
+            let _ = ready.send(());
+        }
+
+        let mut conns = JoinSet::new();
+
+        let req = loop {
+            let (client_sock, client_addr, listener, wait_for_peer) = select! {
+                // Poll in the order written so we check for close first
+                biased;
+
+                req = &mut stop_rx => break req.ok(),
+
+                // Reap connection tasks as they finish so the set doesn't grow unbounded.
+                Some(_) = conns.join_next(), if !conns.is_empty() => continue,
+
+                // Accept errors are never fatal: returning here would drop every connection
+                // without going through the shutdown below.
+                (conn, i) = accept_any(&listeners, self.config.tcp_keepalive) => match conn {
+                    Ok((sock, addr)) => (sock, addr, Some(local_addrs[i].clone()), false),
+                    Err(e) => {
+                        warn!(listener = %local_addrs[i], "could not accept connection: {}", e);
+                        // Errors such as running out of file descriptors last a while, so don't
+                        // spin on them.
+                        if !is_connection_error(&e) {
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        }
+                        continue;
+                    }
+                },
 
-            let close_rx = close_rx.clone();
+                Some((sock, addr, wait)) = incoming_rx.recv() => (sock, addr, None, wait),
+            };
+
+            let (client, channels) = self.conns.register(client_addr, listener);
+            let span = info_span!(
+                "conn",
+                client = %client.id(),
+                peer = %client.addr(),
+                listener = field::Empty,
+                version = field::Empty,
+            );
+            if let Some(listener) = client.listener() {
+                span.record("listener", field::display(listener));
+            }
             let server = self.clone();
-            tokio::spawn(async move {
-                server.handle_conn(&mut client_sock, client_addr, close_rx).await;
+            conns.spawn(
+                async move {
+                    let mut stream = CountingStream::new(client_sock, client.clone());
+                    let reason = server
+                        .run_conn(&mut stream, &client, channels, wait_for_peer)
+                        .await;
+                    server.conns.unregister(client.id());
+                    info!(%reason, "connection closed");
+                }
+                .instrument(span),
+            );
+        };
+
+        // Stop accepting new connections before winding down the existing ones.
+        drop(listeners);
+        info!("server stopping");
+
+        let (drain, done) = match req {
+            Some(StopRequest { drain, done }) => (drain, done),
+            None => (None, None),
+        };
+        match drain {
+            Some(timeout) => {
+                self.conns.drain_all();
+                if !join_all(&mut conns, timeout).await {
+                    warn!(
+                        "{} connection(s) still open after {:?}, closing them",
+                        conns.len(),
+                        timeout
+                    );
+                    self.conns.close_all(DisconnectReason::DrainTimeout);
+                }
+            }
+            None => self.conns.close_all(DisconnectReason::ServerShutdown),
+        }
+
+        // Give connections a moment to notice they are closed and tell the backend, and only then
+        // abort whatever is left.
+        if !join_all(&mut conns, CLOSE_TIMEOUT).await {
+            warn!(
+                "{} connection(s) did not close within {:?}, aborting them",
+                conns.len(),
+                CLOSE_TIMEOUT
+            );
+        }
+        conns.shutdown().await;
+
+        // Aborted connection tasks don't get to clean up after themselves.
+        self.conns.clear();
+
+        self.factory.stop().await;
+        drop(stopped);
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+
+        Ok(())
+    }
+
+    /// Take the stop channel of a running server, moving it to `Stopping`.
+    fn begin_stop(&self) -> Result<oneshot::Sender<StopRequest>, ServerError> {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        match (lifecycle.state, lifecycle.stop_ch.take()) {
+            (ServerState::Running, Some(stop_tx)) => {
+                lifecycle.state = ServerState::Stopping;
+                Ok(stop_tx)
+            }
+            (_, stop_ch) => {
+                // The server may still be binding its listener; leave it be.
+                lifecycle.stop_ch = stop_ch;
+                Err(ServerError::NotRunning)
+            }
+        }
+    }
+
+    /// Stop the server and disconnect any client.
+    ///
+    /// Returns [`ServerError::NotRunning`] if the server isn't running.
+    pub fn stop(self: &Arc<Self>) -> Result<(), ServerError> {
+        let stop_tx = self.begin_stop()?;
+        let _ = stop_tx.send(StopRequest {
+            drain: None,
+            done: None,
+        });
+        Ok(())
+    }
+
+    /// Stop the server gracefully.
+    ///
+    /// The server stops accepting new connections immediately. Each open connection finishes
+    /// what it is doing, sends its client a final update if the client is waiting for one, and
+    /// then closes. Connections still open after `timeout` are closed forcibly. Resolves once
+    /// every connection task has exited and the server is `Stopped`.
+    ///
+    /// Returns [`ServerError::NotRunning`] if the server isn't running.
+    pub async fn stop_gracefully(self: &Arc<Self>, timeout: Duration) -> Result<(), ServerError> {
+        let stop_tx = self.begin_stop()?;
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let req = StopRequest {
+            drain: Some(timeout),
+            done: Some(done_tx),
+        };
+        if stop_tx.send(req).is_ok() {
+            let _ = done_rx.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, UnixStream};
+
+    use std::sync::Mutex as StdMutex;
+
+    use super::{
+        BackendError, DisconnectReason, ExclusivePolicy, OverflowPolicy, Server, ServerError,
+        ServerFactory, ServerState, VncServer, VncServerConfig,
+    };
+    use crate::clients::{ClientContext, ClientId, ClientInfo, ClientRole};
+    use crate::damage::DamageNotifier;
+    use crate::listener::{ListenAddr, PeerAddr};
+    use crate::region::Rect;
+    use crate::rfb::{FenceFlags, ProtoVersion, SecurityType};
+    use crate::testing::{
+        authenticate, connect, connect_with, run, test_config, test_data, test_server,
+        test_server_with, wait_for_connections, TestServer,
+    };
+
+    /// A factory that gives each client a backend, with a damage notifier, of its own, and tells
+    /// the backend when its client goes away.
+    #[derive(Default)]
+    struct PerClientFactory {
+        backends: StdMutex<Vec<(ClientId, Arc<TestServer>)>>,
+    }
+
+    #[async_trait]
+    impl ServerFactory for PerClientFactory {
+        type Server = TestServer;
+
+        async fn create(
+            &self,
+            ctx: &ClientContext,
+            _info: ClientInfo,
+        ) -> Result<Arc<TestServer>, BackendError> {
+            let backend = Arc::new(TestServer {
+                notifier: Some(DamageNotifier::new()),
+                ..Default::default()
             });
+            self.backends
+                .lock()
+                .unwrap()
+                .push((ctx.id, backend.clone()));
+            Ok(backend)
+        }
+
+        async fn client_disconnected(
+            &self,
+            ctx: &ClientContext,
+            info: ClientInfo,
+            reason: DisconnectReason,
+        ) {
+            let backend = (self.backends.lock().unwrap())
+                .iter()
+                .find(|(id, _)| *id == ctx.id)
+                .map(|(_, b)| b.clone());
+            if let Some(backend) = backend {
+                backend.client_disconnected(ctx, info, reason).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_restart() {
+        let server = test_server();
+        assert_eq!(server.state(), ServerState::NotStarted);
+        assert!(matches!(server.stop(), Err(ServerError::NotRunning)));
+
+        let task = run(&server).await;
+        assert_eq!(server.state(), ServerState::Running);
+        assert!(matches!(
+            server.start().await,
+            Err(ServerError::AlreadyRunning)
+        ));
+        assert!(matches!(
+            server.set_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)),
+            Err(ServerError::AlreadyRunning)
+        ));
+
+        server.stop().unwrap();
+        task.await.unwrap();
+        assert_eq!(server.state(), ServerState::Stopped);
+        assert_eq!(server.local_addr(), None);
+
+        // The same server can be started again.
+        let task = run(&server).await;
+        assert_eq!(server.state(), ServerState::Running);
+        server
+            .stop_gracefully(Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(server.state(), ServerState::Stopped);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let path = std::env::temp_dir().join(format!("rfb-test-{}.sock", std::process::id()));
+        let tcp = ListenAddr::from(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+        let unix = ListenAddr::Unix(path.clone());
+        let server = test_server_with(VncServerConfig {
+            listen: vec![tcp, unix.clone()],
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let local_addrs = server.local_addrs();
+        assert_eq!(local_addrs.len(), 2);
+        assert_eq!(local_addrs[1], unix);
+
+        let mut tcp_client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut unix_client = UnixStream::connect(&path).await.unwrap();
+        let mut version = [0u8; 12];
+        tcp_client.read_exact(&mut version).await.unwrap();
+        unix_client.read_exact(&mut version).await.unwrap();
+
+        let mut clients: Vec<_> = server.clients().iter().map(|c| c.info()).collect();
+        clients.sort_by_key(|c| c.id);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].listener.as_ref(), Some(&local_addrs[0]));
+        assert!(matches!(clients[0].addr, PeerAddr::Tcp(_)));
+        assert_eq!(clients[1].listener.as_ref(), Some(&unix));
+        assert!(matches!(clients[1].addr, PeerAddr::Unix(_)));
+
+        // The socket file is cleaned up once the server stops.
+        server.stop().unwrap();
+        task.await.unwrap();
+        assert!(server.local_addrs().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_stop_gracefully_closes_connections() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        server
+            .stop_gracefully(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(server.state(), ServerState::Stopped);
+
+        // The server closed its end of the connection.
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_gracefully_sends_final_update() {
+        let server = test_server_with(VncServerConfig {
+            update_poll_interval: None,
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+        let request = [3, 1, 0, 0, 0, 0, 0, 16, 0, 16];
+        client.write_all(&request).await.unwrap();
+        let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+        client.read_exact(&mut update).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        while server.factory.server().polled.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // A change nobody reported is still sent to the waiting client before it is closed.
+        let mut pixels = vec![0u8; 16 * 16 * 4];
+        pixels[(3 * 16 + 2) * 4] = 0xff;
+        *server.factory.server().pixels.lock().unwrap() = pixels;
+        server
+            .stop_gracefully(Duration::from_secs(5))
+            .await
+            .unwrap();
+        let mut update = [0u8; 4 + 12 + 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[4..12], &[0, 2, 0, 3, 0, 1, 0, 1]);
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        task.await.unwrap();
+
+        let events = server.factory.server().events.lock().unwrap().clone();
+        assert!(events.last().unwrap().ends_with("server shutting down"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_notifies_backend() {
+        let server = test_server();
+        let task = run(&server).await;
+        let _client = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+        let id = server.clients()[0].id();
+
+        // Even when stopped at once, the backend hears why each client went away.
+        server.stop().unwrap();
+        task.await.unwrap();
+        let events = server.factory.server().events.lock().unwrap().clone();
+        assert_eq!(
+            events.last(),
+            Some(&format!("disconnected {}: server shutting down", id))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overflow_queue() {
+        let server = test_server_with(VncServerConfig {
+            max_connections: Some(1),
+            overflow_policy: OverflowPolicy::Queue,
+            queue_timeout: Duration::from_millis(200),
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let _first = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // The second client waits for a slot, and is refused once it has waited too long.
+        let mut second = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let mut version = [0u8; 12];
+        second.read_exact(&mut version).await.unwrap();
+        second.write_all(&version).await.unwrap();
+        assert_eq!(second.read_u8().await.unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let mut reason = vec![0u8; second.read_u32().await.unwrap() as usize];
+        second.read_exact(&mut reason).await.unwrap();
+        assert_eq!(reason, b"too many connections");
+        assert_eq!(server.queued_connection_count(), 0);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_refuse() {
+        let server = test_server_with(VncServerConfig {
+            max_connections: Some(1),
+            overflow_policy: OverflowPolicy::Refuse,
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let _first = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // The second client gets the version handshake followed by a failure reason.
+        let mut second = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut version = [0u8; 12];
+        second.read_exact(&mut version).await.unwrap();
+        second.write_all(&version).await.unwrap();
+        assert_eq!(second.read_u8().await.unwrap(), 0);
+        let mut reason = vec![0u8; second.read_u32().await.unwrap() as usize];
+        second.read_exact(&mut reason).await.unwrap();
+        assert_eq!(reason, b"too many connections");
+        assert_eq!(server.connection_count(), 1);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_disconnect_oldest() {
+        let server = test_server_with(VncServerConfig {
+            max_connections: Some(1),
+            overflow_policy: OverflowPolicy::DisconnectOldest,
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let mut first = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // The second client takes over the only slot, and the first is disconnected.
+        let _second = connect(&server).await;
+        let mut buf = [0u8; 1];
+        assert_eq!(first.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 1).await;
+        assert_eq!(server.queued_connection_count(), 0);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let server = test_server_with(VncServerConfig {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // Never send anything; the server should hang up on us.
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 0).await;
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fences_are_not_activity() {
+        let server = test_server_with(VncServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let (mut rd, mut wr) = connect(&server).await.into_split();
+
+        // Answering fences, without any input, still leaves the client idle.
+        let fences = tokio::spawn(async move {
+            let fence = [248, 0, 0, 0, 0, 0, 0, 0, 0];
+            while wr.write_all(&fence).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let mut buf = [0u8; 1];
+        let closed = tokio::time::timeout(Duration::from_secs(1), rd.read(&mut buf)).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        fences.abort();
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_handles() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        let clients = server.clients();
+        assert_eq!(clients.len(), 1);
+        let handle = &clients[0];
+        let info = handle.info();
+        assert_eq!(info.version, Some(ProtoVersion::Rfb38));
+        assert_eq!(info.security_type, Some(SecurityType::None));
+        assert!(info.bytes_in > 0 && info.bytes_out > 0);
+
+        // Messages pushed through the handle arrive at the client.
+        handle.bell().unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), 2);
+        handle.cut_text("hello".to_string()).unwrap();
+        let mut cut_text = [0u8; 4 + 4 + 5];
+        client.read_exact(&mut cut_text).await.unwrap();
+        assert_eq!(&cut_text, b"\x03\0\0\0\0\0\0\x05hello");
+
+        handle.disconnect().unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 0).await;
+        while handle.is_connected() {
+            tokio::task::yield_now().await;
         }
+        assert!(matches!(
+            handle.bell(),
+            Err(ServerError::ClientDisconnected(_))
+        ));
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_callbacks() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+        let id = server.clients()[0].id();
+
+        // SetEncodings: Raw, CopyRect
+        client
+            .write_all(&[2, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        drop(client);
+        wait_for_connections(&server, 0).await;
+        server.stop().unwrap();
+        task.await.unwrap();
+
+        assert_eq!(
+            *server.factory.server().events.lock().unwrap(),
+            vec![
+                format!("connected {}", id),
+                format!("authenticated {}", id),
+                format!("capabilities {}: [Raw, CopyRect]", id),
+                format!("disconnected {}: client closed connection", id),
+            ]
+        );
     }
 
-    /// Stop the server (and disconnect any client) if it's running.
-    pub async fn stop(self: &Arc<Self>) {
-        if let Some(close_tx) = self.stop_ch.lock().await.take() {
-            let _ = close_tx.send(());
+    #[tokio::test]
+    async fn test_backend_error_closes_session() {
+        let server = test_server();
+        *server.factory.server().update_error.lock().unwrap() =
+            Some(BackendError::Fatal("display unavailable".to_string()));
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // FramebufferUpdateRequest for the whole screen
+        client
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, 16, 0, 16])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 0).await;
+
+        let events = server.factory.server().events.lock().unwrap().clone();
+        assert!(events
+            .last()
+            .unwrap()
+            .ends_with("backend error: display unavailable"));
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_context() {
+        for role in [ClientRole::Interactive, ClientRole::ViewOnly] {
+            let server = test_server();
+            *server.factory.server().role.lock().unwrap() = role;
+            let task = run(&server).await;
+            let mut client = connect(&server).await;
+            wait_for_connections(&server, 1).await;
+            let info = server.clients()[0].info();
+            assert_eq!(info.identity, Some(format!("user{}", info.id)));
+            assert_eq!(info.role, role);
+
+            // KeyEvent: press 'a'
+            client
+                .write_all(&[4, 1, 0, 0, 0, 0, 0, 0x61])
+                .await
+                .unwrap();
+            drop(client);
+            wait_for_connections(&server, 0).await;
+            server.stop().unwrap();
+            task.await.unwrap();
+
+            let key = format!("key 97 from Some(\"user{}\")", info.id);
+            let events = server.factory.server().events.lock().unwrap().clone();
+            assert_eq!(events.contains(&key), role == ClientRole::Interactive);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // FramebufferUpdateRequest for the whole screen, then a KeyEvent.
+        client
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, 16, 0, 16])
+            .await
+            .unwrap();
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).await.unwrap();
+        client
+            .write_all(&[4, 1, 0, 0, 0, 0, 0, 0x61])
+            .await
+            .unwrap();
+        while server.metrics().totals.key_events == 0 {
+            tokio::task::yield_now().await;
         }
+
+        let metrics = server.clients()[0].metrics();
+        assert_eq!(metrics.updates_sent, 1);
+        assert_eq!(metrics.key_events, 1);
+        assert!(metrics.bytes_in > 0 && metrics.bytes_out > 0);
+
+        // Totals keep counting connections after they close.
+        drop(client);
+        wait_for_connections(&server, 0).await;
+        while !server.metrics().connections.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let totals = server.metrics();
+        assert_eq!(totals.connections_accepted, 1);
+        assert_eq!(totals.connections_active, 0);
+        assert_eq!(totals.totals.updates_sent, 1);
+        assert_eq!(totals.totals.bytes_out, metrics.bytes_out);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incremental_update_waits() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // The first incremental request gets the whole 16x16 screen as a Raw rectangle.
+        let request = [3, 1, 0, 0, 0, 0, 0, 16, 0, 16];
+        client.write_all(&request).await.unwrap();
+        let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[..4], &[0, 0, 0, 1]);
+
+        // Nothing has changed since, so the next one isn't answered.
+        client.write_all(&request).await.unwrap();
+        let mut buf = [0u8; 1];
+        let res = tokio::time::timeout(Duration::from_millis(200), client.read(&mut buf)).await;
+        assert!(res.is_err());
+        assert_eq!(server.metrics().totals.updates_sent, 1);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_clipped_to_request() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // A non-incremental request for part of the screen gets just that part.
+        let request = [3, 0, 0, 4, 0, 2, 0, 8, 0, 3];
+        client.write_all(&request).await.unwrap();
+        let mut update = [0u8; 4 + 12 + 8 * 3 * 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[..4], &[0, 0, 0, 1]);
+        assert_eq!(&update[4..12], &[0, 4, 0, 2, 0, 8, 0, 3]);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zlib_encoding() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // SetEncodings: Zlib, Raw. With nothing measured yet, the client's preference is used.
+        client
+            .write_all(&[2, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        client
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, 16, 0, 16])
+            .await
+            .unwrap();
+        let mut header = [0u8; 4 + 12 + 4];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(&header[..4], &[0, 0, 0, 1]);
+        assert_eq!(&header[12..16], &6i32.to_be_bytes());
+
+        let len = u32::from_be_bytes(header[16..].try_into().unwrap());
+        let mut data = vec![0u8; len as usize];
+        client.read_exact(&mut data).await.unwrap();
+        let mut pixels = Vec::with_capacity(16 * 16 * 4);
+        flate2::Decompress::new(true)
+            .decompress_vec(&data, &mut pixels, flate2::FlushDecompress::Sync)
+            .unwrap();
+        assert_eq!(pixels.len(), 16 * 16 * 4);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_continuous_updates() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // SetEncodings: Raw, ContinuousUpdates. The server says it supports the extension.
+        let mut set_encodings = vec![2, 0, 0, 2, 0, 0, 0, 0];
+        set_encodings.extend_from_slice(&(-313i32).to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 150);
+
+        // EnableContinuousUpdates for the whole screen gets it straight away...
+        let enable = [150, 1, 0, 0, 0, 0, 0, 16, 0, 16];
+        client.write_all(&enable).await.unwrap();
+        let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[..4], &[0, 0, 0, 1]);
+
+        // ...and then each change, without asking again.
+        let mut pixels = vec![0u8; 16 * 16 * 4];
+        pixels[(3 * 16 + 2) * 4] = 0xff;
+        *server.factory.server().pixels.lock().unwrap() = pixels;
+        server.damage_notifier().damage(Rect::new(2, 3, 1, 1));
+        let mut update = [0u8; 4 + 12 + 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[4..12], &[0, 2, 0, 3, 0, 1, 0, 1]);
+
+        // Disabling them is confirmed with EndOfContinuousUpdates.
+        let disable = [150, 0, 0, 0, 0, 0, 0, 16, 0, 16];
+        client.write_all(&disable).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 150);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fence_answered() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        // A fence request is answered with the same payload, without the request flag.
+        let flags = FenceFlags::REQUEST | FenceFlags::BLOCK_BEFORE;
+        let mut fence = vec![248, 0, 0, 0];
+        fence.extend_from_slice(&flags.bits().to_be_bytes());
+        fence.extend_from_slice(&[3, 1, 2, 3]);
+        client.write_all(&fence).await.unwrap();
+
+        let mut reply = [0u8; 4 + 4 + 1 + 3];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], 248);
+        assert_eq!(&reply[4..8], &FenceFlags::BLOCK_BEFORE.bits().to_be_bytes());
+        assert_eq!(&reply[8..], &[3, 1, 2, 3]);
+
+        // One asking to be synchronised with the next message is answered after it, and only
+        // with the flags the server understands.
+        let flags =
+            FenceFlags::REQUEST | FenceFlags::SYNC_NEXT | FenceFlags::from_bits_retain(1 << 5);
+        let mut fence = vec![248, 0, 0, 0];
+        fence.extend_from_slice(&flags.bits().to_be_bytes());
+        fence.push(0);
+        client.write_all(&fence).await.unwrap();
+        client
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, 1, 0, 1])
+            .await
+            .unwrap();
+
+        let mut update = [0u8; 4 + 12 + 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(update[0], 0);
+        let mut reply = [0u8; 4 + 4 + 1];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], 248);
+        assert_eq!(&reply[4..8], &FenceFlags::SYNC_NEXT.bits().to_be_bytes());
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_update_rate() {
+        let server = test_server_with(VncServerConfig {
+            max_update_rate: Some(4),
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        let request = [3, 0, 0, 0, 0, 0, 0, 16, 0, 16];
+        let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+        client.write_all(&request).await.unwrap();
+        client.read_exact(&mut update).await.unwrap();
+        let first = Instant::now();
+
+        // A request made straight away is only answered once a quarter of a second has passed.
+        client.write_all(&request).await.unwrap();
+        client.read_exact(&mut update).await.unwrap();
+        assert!(first.elapsed() >= Duration::from_millis(240));
+        assert_eq!(server.metrics().totals.updates_sent, 2);
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_damage_notification() {
+        let server = test_server_with(VncServerConfig {
+            update_poll_interval: None,
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let mut client = connect(&server).await;
+
+        let request = [3, 1, 0, 0, 0, 0, 0, 16, 0, 16];
+        client.write_all(&request).await.unwrap();
+        let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+        client.read_exact(&mut update).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = [0u8; 1];
+        let res = tokio::time::timeout(Duration::from_millis(200), client.read(&mut buf)).await;
+        assert!(res.is_err());
+
+        // Without polling, a change only reaches the client once it is reported.
+        let mut pixels = vec![0u8; 16 * 16 * 4];
+        pixels[(3 * 16 + 2) * 4] = 0xff;
+        *server.factory.server().pixels.lock().unwrap() = pixels;
+        let res = tokio::time::timeout(Duration::from_millis(200), client.read(&mut buf)).await;
+        assert!(res.is_err());
+
+        server.damage_notifier().damage(Rect::new(2, 3, 1, 1));
+        let mut update = [0u8; 4 + 12 + 4];
+        client.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[..4], &[0, 0, 0, 1]);
+        // The rectangle covers just the changed pixel, and only that was asked of the backend.
+        assert_eq!(&update[4..12], &[0, 2, 0, 3, 0, 1, 0, 1]);
+        assert_eq!(update[16], 0xff);
+        let polled = server.factory.server().polled.lock().unwrap().clone();
+        assert_eq!(polled.last(), Some(&Rect::new(2, 3, 1, 1)));
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backend_damage_notifier() {
+        let config = VncServerConfig {
+            update_poll_interval: None,
+            ..test_config()
+        };
+        let server = VncServer::with_factory(PerClientFactory::default(), config, test_data());
+        let task = run(&server).await;
+        let request = [3, 1, 0, 0, 0, 0, 0, 16, 0, 16];
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = connect(&server).await;
+            client.write_all(&request).await.unwrap();
+            let mut update = [0u8; 4 + 12 + 16 * 16 * 4];
+            client.read_exact(&mut update).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            clients.push(client);
+        }
+        let backends: Vec<_> = (server.factory.backends.lock().unwrap())
+            .iter()
+            .map(|(_, b)| b.clone())
+            .collect();
+        // Wait for the second requests to have found nothing to send.
+        while backends.iter().any(|b| b.polled.lock().unwrap().len() < 2) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Both framebuffers change, but only the first backend reports it, which wakes only the
+        // client using it.
+        let mut pixels = vec![0u8; 16 * 16 * 4];
+        pixels[(3 * 16 + 2) * 4] = 0xff;
+        for backend in &backends {
+            *backend.pixels.lock().unwrap() = pixels.clone();
+        }
+        backends[0]
+            .notifier
+            .as_ref()
+            .unwrap()
+            .damage(Rect::new(2, 3, 1, 1));
+        let mut update = [0u8; 4 + 12 + 4];
+        clients[0].read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[4..12], &[0, 2, 0, 3, 0, 1, 0, 1]);
+        let mut buf = [0u8; 1];
+        let res = tokio::time::timeout(Duration::from_millis(200), clients[1].read(&mut buf)).await;
+        assert!(res.is_err());
+
+        // The server-wide notifier doesn't reach connections whose backend has its own.
+        server.damage_notifier().damage(Rect::new(2, 3, 1, 1));
+        let res = tokio::time::timeout(Duration::from_millis(200), clients[1].read(&mut buf)).await;
+        assert!(res.is_err());
+
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_per_client_backends() {
+        let server =
+            VncServer::with_factory(PerClientFactory::default(), test_config(), test_data());
+        let task = run(&server).await;
+        let mut first = connect(&server).await;
+        let mut second = connect(&server).await;
+        wait_for_connections(&server, 2).await;
+
+        // KeyEvent: press 'a' from the first client, 'b' from the second
+        first.write_all(&[4, 1, 0, 0, 0, 0, 0, 0x61]).await.unwrap();
+        second
+            .write_all(&[4, 1, 0, 0, 0, 0, 0, 0x62])
+            .await
+            .unwrap();
+        drop(first);
+        drop(second);
+        wait_for_connections(&server, 0).await;
+        server.stop().unwrap();
+        task.await.unwrap();
+
+        // Each backend only sees its own client, and hears of the other lifecycle callbacks only
+        // because the factory passes on the disconnect.
+        let backends = server.factory.backends.lock().unwrap();
+        assert_eq!(backends.len(), 2);
+        let mut keys: Vec<_> = backends
+            .iter()
+            .map(|(id, backend)| {
+                let events = backend.events.lock().unwrap();
+                assert_eq!(events.len(), 2, "client {}: {:?}", id, events);
+                assert!(events[1].starts_with(&format!("disconnected {}:", id)));
+                events[0].split(' ').nth(1).unwrap().to_string()
+            })
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["97", "98"]);
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_disconnects_others() {
+        let server = test_server();
+        let task = run(&server).await;
+        let mut first = connect(&server).await;
+        let second = connect(&server).await;
+        wait_for_connections(&server, 2).await;
+        let ids: Vec<_> = server.clients().iter().map(|c| c.id()).collect();
+
+        let third = connect_with(&server, false).await;
+        let mut buf = [0u8; 1];
+        assert_eq!(first.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 1).await;
+        assert_eq!(server.clients()[0].info().shared, Some(false));
+
+        let events = server.factory.server().events.lock().unwrap().clone();
+        for id in ids {
+            assert!(events.contains(&format!(
+                "disconnected {}: another client took exclusive access",
+                id
+            )));
+        }
+
+        drop((second, third));
+        server.stop().unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_refused() {
+        let server = test_server_with(VncServerConfig {
+            exclusive_policy: ExclusivePolicy::Refuse,
+            ..test_config()
+        });
+        let task = run(&server).await;
+        let _first = connect(&server).await;
+        wait_for_connections(&server, 1).await;
+
+        // A second client asking for exclusive access is closed before ServerInit.
+        let mut second = authenticate(&server).await;
+        second.write_u8(0).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&server, 1).await;
+
+        // Shared clients are still let in.
+        let _third = connect(&server).await;
+        wait_for_connections(&server, 2).await;
+
+        server.stop().unwrap();
+        task.await.unwrap();
     }
 }
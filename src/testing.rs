@@ -0,0 +1,211 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Fixtures shared by the tests of the server and the modules built on it
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::clients::{Authorization, ClientContext, ClientInfo, ClientRole};
+use crate::damage::DamageNotifier;
+use crate::encodings::RawEncoding;
+use crate::pixel_formats::fourcc;
+use crate::region::Rect;
+use crate::rfb::{
+    FramebufferUpdate, KeyEvent, ProtoVersion, Rectangle, SecurityType, SecurityTypes,
+};
+use crate::server::{
+    BackendError, DisconnectReason, Server, ServerFactory, VncServer, VncServerConfig,
+    VncServerData,
+};
+
+/// A backend that draws nothing and records the lifecycle callbacks it receives.
+#[derive(Default)]
+pub(crate) struct TestServer {
+    pub(crate) events: Mutex<Vec<String>>,
+
+    /// If set, framebuffer updates fail with this error.
+    pub(crate) update_error: Mutex<Option<BackendError>>,
+
+    /// The role given to clients when they authenticate.
+    pub(crate) role: Mutex<ClientRole>,
+
+    /// Raw pixels for the whole screen, or empty to draw nothing.
+    pub(crate) pixels: Mutex<Vec<u8>>,
+
+    /// The areas framebuffer updates were asked for.
+    pub(crate) polled: Mutex<Vec<Rect>>,
+
+    /// The backend's own damage notifier, if it has one.
+    pub(crate) notifier: Option<DamageNotifier>,
+}
+
+impl TestServer {
+    pub(crate) fn record(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[async_trait]
+impl Server for TestServer {
+    async fn get_framebuffer_update(
+        &self,
+        _ctx: &ClientContext,
+        area: Rect,
+    ) -> Result<FramebufferUpdate, BackendError> {
+        self.polled.lock().unwrap().push(area);
+        match self.update_error.lock().unwrap().clone() {
+            Some(e) => Err(e),
+            None => {
+                let pixels = self.pixels.lock().unwrap().clone();
+                if pixels.is_empty() {
+                    return Ok(FramebufferUpdate::new(vec![]));
+                }
+                let r = Rectangle::new(0, 0, 16, 16, Box::new(RawEncoding::new(pixels)));
+                Ok(FramebufferUpdate::new(vec![r]))
+            }
+        }
+    }
+
+    async fn key_event(&self, ctx: &ClientContext, ke: KeyEvent) -> Result<(), BackendError> {
+        self.record(format!("key {} from {:?}", ke.keysym_raw(), ctx.identity));
+        Ok(())
+    }
+
+    async fn client_connected(
+        &self,
+        _ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        self.record(format!("connected {}", info.id));
+        Ok(())
+    }
+
+    async fn client_authenticated(
+        &self,
+        _ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<Authorization, BackendError> {
+        self.record(format!("authenticated {}", info.id));
+        Ok(Authorization {
+            identity: Some(format!("user{}", info.id)),
+            role: *self.role.lock().unwrap(),
+        })
+    }
+
+    async fn client_disconnected(
+        &self,
+        _ctx: &ClientContext,
+        info: ClientInfo,
+        reason: DisconnectReason,
+    ) {
+        self.record(format!("disconnected {}: {}", info.id, reason));
+    }
+
+    async fn client_capabilities_changed(
+        &self,
+        _ctx: &ClientContext,
+        info: ClientInfo,
+    ) -> Result<(), BackendError> {
+        self.record(format!("capabilities {}: {:?}", info.id, info.encodings));
+        Ok(())
+    }
+
+    fn damage_notifier(&self) -> Option<DamageNotifier> {
+        self.notifier.clone()
+    }
+}
+
+pub(crate) fn test_config() -> VncServerConfig {
+    VncServerConfig {
+        listen: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0).into()],
+        version: ProtoVersion::Rfb38,
+        sec_types: SecurityTypes(vec![SecurityType::None]),
+        name: "test".to_string(),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn test_server() -> Arc<VncServer<TestServer>> {
+    test_server_with(test_config())
+}
+
+pub(crate) fn test_server_with(config: VncServerConfig) -> Arc<VncServer<TestServer>> {
+    VncServer::new(TestServer::default(), config, test_data())
+}
+
+pub(crate) fn test_data() -> VncServerData {
+    VncServerData {
+        width: 16,
+        height: 16,
+        input_pixel_format: fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap(),
+    }
+}
+
+/// Start the server in the background and wait for it to bind its listener.
+pub(crate) async fn run<S: Server, F: ServerFactory<Server = S>>(
+    server: &Arc<VncServer<S, F>>,
+) -> tokio::task::JoinHandle<()> {
+    let s = server.clone();
+    let task = tokio::spawn(async move { s.start().await.unwrap() });
+    while server.local_addr().is_none() {
+        tokio::task::yield_now().await;
+    }
+    task
+}
+
+/// Connect to the server and complete the security handshake.
+pub(crate) async fn authenticate<S: Server, F: ServerFactory<Server = S>>(
+    server: &Arc<VncServer<S, F>>,
+) -> TcpStream {
+    let mut client = TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut version = [0u8; 12];
+    client.read_exact(&mut version).await.unwrap();
+    client.write_all(&version).await.unwrap();
+    let mut sec_types = [0u8; 2];
+    client.read_exact(&mut sec_types).await.unwrap();
+    client.write_u8(1).await.unwrap();
+    assert_eq!(client.read_u32().await.unwrap(), 0);
+    client
+}
+
+/// Connect to the server and complete the handshake and initialization, so the connection is
+/// in its message loop.
+pub(crate) async fn connect<S: Server, F: ServerFactory<Server = S>>(
+    server: &Arc<VncServer<S, F>>,
+) -> TcpStream {
+    connect_with(server, true).await
+}
+
+/// Connect to the server as a shared or exclusive client, as for [`connect`].
+pub(crate) async fn connect_with<S: Server, F: ServerFactory<Server = S>>(
+    server: &Arc<VncServer<S, F>>,
+    shared: bool,
+) -> TcpStream {
+    let mut client = authenticate(server).await;
+    client.write_u8(shared as u8).await.unwrap();
+    let mut server_init = [0u8; 24];
+    client.read_exact(&mut server_init).await.unwrap();
+    let mut name = vec![0u8; u32::from_be_bytes(server_init[20..].try_into().unwrap()) as usize];
+    client.read_exact(&mut name).await.unwrap();
+    client
+}
+
+/// Wait until the server has `n` clients connected.
+pub(crate) async fn wait_for_connections<S: Server, F: ServerFactory<Server = S>>(
+    server: &Arc<VncServer<S, F>>,
+    n: usize,
+) {
+    while server.connection_count() != n {
+        tokio::task::yield_now().await;
+    }
+}
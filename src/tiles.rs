@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Change detection between frames
+//!
+//! The framebuffer is divided into a grid of square tiles, and successive frames are compared
+//! tile by tile. Each connection does this against its copy of what the client has, so a backend
+//! that can only hand over whole frames still gets incremental updates. Backends that want to
+//! report damage themselves, without keeping a copy of the previous frame, can use a
+//! [`ChangeDetector`], which remembers a hash of each tile instead.
+
+use std::hash::{DefaultHasher, Hasher};
+
+use crate::framebuffer::FramebufferError;
+use crate::region::{Rect, Region};
+
+/// The width and height of a tile, in pixels.
+pub const TILE_SIZE: u16 = 64;
+
+/// The pieces of `rect` falling in each tile of the grid, in rows from top to bottom.
+pub(crate) fn tiles(rect: Rect, size: u16) -> impl Iterator<Item = Rect> {
+    let size = size as u32;
+    let rows = (rect.y as u32 / size * size..rect.bottom()).step_by(size as usize);
+    rows.flat_map(move |ty| {
+        let cols = (rect.x as u32 / size * size..rect.right()).step_by(size as usize);
+        cols.filter_map(move |tx| {
+            let tile = Rect::new(tx as u16, ty as u16, size as u16, size as u16);
+            tile.intersection(&rect)
+        })
+    })
+}
+
+/// Combine changed parts of tiles, given in the order [`tiles`] produces them, into a region.
+///
+/// Neighbouring tiles in the same row are joined into one rectangle, and rows of tiles with the
+/// same extent are joined together, so a large change becomes a few large rectangles rather than
+/// many tile-sized ones.
+pub(crate) fn merge(changed: impl IntoIterator<Item = (Rect, Rect)>) -> Region {
+    // Each entry is a changed part of a tile, along with the tile itself.
+    let mut runs: Vec<(Rect, Rect)> = Vec::new();
+    for (part, tile) in changed {
+        match runs.last_mut() {
+            Some((run, run_tiles))
+                if run_tiles.y == tile.y && run_tiles.right() == tile.x as u32 =>
+            {
+                *run = run.union(&part);
+                *run_tiles = run_tiles.union(&tile);
+            }
+            _ => runs.push((part, tile)),
+        }
+    }
+
+    let mut rects: Vec<Rect> = Vec::new();
+    for (run, _) in runs {
+        let below = rects
+            .iter_mut()
+            .find(|r| r.x == run.x && r.width == run.width && r.bottom() == run.y as u32);
+        match below {
+            Some(r) => r.height += run.height,
+            None => rects.push(run),
+        }
+    }
+
+    let mut region = Region::new();
+    for r in rects {
+        region.add(r);
+    }
+    region
+}
+
+/// Finds the parts of a framebuffer that changed between frames, by remembering a hash of each
+/// tile of the last frame it was shown.
+pub struct ChangeDetector {
+    width: u16,
+    height: u16,
+    stride: usize,
+    bytes_per_pixel: usize,
+    hashes: Vec<u64>,
+}
+
+impl Default for ChangeDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeDetector {
+    pub fn new() -> Self {
+        ChangeDetector {
+            width: 0,
+            height: 0,
+            stride: 0,
+            bytes_per_pixel: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Compare a frame of `width` by `height` pixels, whose rows are `stride` bytes apart, with
+    /// the last one, returning what changed. The first frame, and any frame of a different size
+    /// or layout, is entirely changed.
+    pub fn detect(
+        &mut self,
+        width: u16,
+        height: u16,
+        stride: usize,
+        bytes_per_pixel: usize,
+        pixels: &[u8],
+    ) -> Result<Region, FramebufferError> {
+        if stride < width as usize * bytes_per_pixel {
+            return Err(FramebufferError::StrideTooShort {
+                stride,
+                width,
+                bytes_per_pixel,
+            });
+        }
+        let expected = stride * height as usize;
+        if pixels.len() != expected {
+            return Err(FramebufferError::WrongLength {
+                expected,
+                actual: pixels.len(),
+            });
+        }
+
+        let bounds = Rect::new(0, 0, width, height);
+        let layout = (width, height, stride, bytes_per_pixel);
+        let resized = layout != (self.width, self.height, self.stride, self.bytes_per_pixel);
+        if resized {
+            self.width = width;
+            self.height = height;
+            self.stride = stride;
+            self.bytes_per_pixel = bytes_per_pixel;
+            self.hashes.clear();
+        }
+
+        let mut changed = Vec::new();
+        for (i, tile) in tiles(bounds, TILE_SIZE).enumerate() {
+            let mut hasher = DefaultHasher::new();
+            let row_len = tile.width as usize * bytes_per_pixel;
+            for y in tile.y as usize..tile.bottom() as usize {
+                let start = y * stride + tile.x as usize * bytes_per_pixel;
+                hasher.write(&pixels[start..][..row_len]);
+            }
+            let hash = hasher.finish();
+
+            if resized {
+                self.hashes.push(hash);
+            } else if self.hashes[i] != hash {
+                self.hashes[i] = hash;
+                changed.push((tile, tile));
+            }
+        }
+
+        if resized {
+            return Ok(Region::from(bounds));
+        }
+        Ok(merge(changed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tiles, ChangeDetector, TILE_SIZE};
+    use crate::framebuffer::FramebufferError;
+    use crate::region::Rect;
+
+    #[test]
+    fn test_tiles() {
+        let parts: Vec<_> = tiles(Rect::new(60, 0, 10, 70), TILE_SIZE).collect();
+        assert_eq!(
+            parts,
+            vec![
+                Rect::new(60, 0, 4, 64),
+                Rect::new(64, 0, 6, 64),
+                Rect::new(60, 64, 4, 6),
+                Rect::new(64, 64, 6, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_change_detector() {
+        const WIDTH: u16 = 200;
+        const HEIGHT: u16 = 150;
+        let stride = WIDTH as usize * 4;
+        let mut pixels = vec![0u8; stride * HEIGHT as usize];
+        let mut detector = ChangeDetector::new();
+
+        let all = Rect::new(0, 0, WIDTH, HEIGHT);
+        assert_eq!(
+            detector
+                .detect(WIDTH, HEIGHT, stride, 4, &pixels)
+                .unwrap()
+                .rects(),
+            &[all]
+        );
+        assert!(detector
+            .detect(WIDTH, HEIGHT, stride, 4, &pixels)
+            .unwrap()
+            .is_empty());
+
+        // Two separate changes each mark just their own tile...
+        pixels[10 * stride + 10 * 4] = 1;
+        pixels[100 * stride + 150 * 4] = 1;
+        let changed = detector.detect(WIDTH, HEIGHT, stride, 4, &pixels).unwrap();
+        assert_eq!(
+            changed.rects(),
+            &[Rect::new(0, 0, 64, 64), Rect::new(128, 64, 64, 64)]
+        );
+
+        // ...while changing everything gives back a single rectangle.
+        pixels.iter_mut().for_each(|p| *p = 2);
+        let changed = detector.detect(WIDTH, HEIGHT, stride, 4, &pixels).unwrap();
+        assert_eq!(changed.rects(), &[all]);
+    }
+
+    #[test]
+    fn test_change_detector_layout() {
+        let mut detector = ChangeDetector::new();
+        let all = Rect::new(0, 0, 16, 16);
+        let pixels = vec![0u8; 16 * 16 * 4];
+        detector.detect(16, 16, 16 * 4, 4, &pixels).unwrap();
+
+        // A buffer too short for the frame is refused, rather than read past its end.
+        let res = detector.detect(16, 16, 16 * 4, 4, &pixels[1..]);
+        assert!(matches!(res, Err(FramebufferError::WrongLength { .. })));
+        let res = detector.detect(16, 16, 16, 4, &pixels);
+        assert!(matches!(res, Err(FramebufferError::StrideTooShort { .. })));
+
+        // The same bytes laid out differently are a different frame.
+        let changed = detector.detect(16, 16, 16 * 2, 2, &pixels[..16 * 16 * 2]);
+        assert_eq!(changed.unwrap().rects(), &[all]);
+    }
+}
@@ -0,0 +1,716 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Incremental updates
+//!
+//! Each connection keeps a copy of the framebuffer as its client will see it, along with the
+//! regions of that copy the client hasn't been sent yet. Frames from the backend are compared
+//! against the copy, tile by tile, to find what changed, so incremental update requests are
+//! answered with just the changed regions, and wait while nothing has changed.
+//!
+//! Clients that support CopyRect are also told about parts of the framebuffer that moved, rather
+//! than being sent their pixels again. Backends can say what moved by including CopyRect
+//! rectangles in their frames, and blocks of a frame that scrolled vertically are found by
+//! comparing its rows with the last frame's.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hasher};
+use std::time::Instant;
+
+use crate::encodings::{CopyRectEncoding, EncodingType, RawEncoding};
+use crate::link::LinkState;
+use crate::region::{Rect, Region};
+use crate::rfb::{Fence, FramebufferUpdate, PixelFormat, Rectangle};
+use crate::tiles::{self, TILE_SIZE};
+
+/// The fewest rows worth sending as a CopyRect when a frame scrolls.
+const MIN_SCROLL_ROWS: usize = 8;
+
+/// How many fences sent after continuous updates may go unanswered before updates stop until one
+/// is answered.
+const MAX_FENCES_IN_FLIGHT: usize = 2;
+
+/// A connection's view of the framebuffer, and what it is still owed.
+pub(crate) struct UpdateState {
+    width: u16,
+    height: u16,
+
+    /// The pixel format of `pixels`, as supplied by the backend.
+    input_format: Option<PixelFormat>,
+    bytes_per_pixel: usize,
+    pixels: Vec<u8>,
+
+    /// The pixel format the client asked for.
+    pub output_format: PixelFormat,
+
+    /// Whether the client accepts CopyRect rectangles.
+    pub copy_rect: bool,
+
+    /// Moves the client hasn't been told about, as a destination and the position it was copied
+    /// from, in the order they were made.
+    copies: Vec<(Rect, u16, u16)>,
+
+    /// Parts of `pixels` the client hasn't been sent.
+    damage: Region,
+
+    /// Rectangles from the backend in encodings the server can't decode, forwarded to the client
+    /// as they are.
+    passthrough: Vec<Rectangle>,
+
+    /// When the client asked for an update that hasn't been sent yet.
+    pending: Option<Instant>,
+
+    /// The parts of the framebuffer the client asked to be updated.
+    requested: Region,
+
+    /// When the backend was last asked for a frame.
+    pub last_poll: Instant,
+
+    /// When the client was last sent an update.
+    last_sent: Option<Instant>,
+
+    /// Whether an update was held back to keep to the update rate limit.
+    pub deferred: bool,
+
+    /// The client's network link, and the encoding chosen for it.
+    pub link: LinkState,
+
+    /// The region the client gets updates of without asking, if it enabled continuous updates.
+    continuous: Option<Rect>,
+
+    /// Whether the client said it supports the ContinuousUpdates extension.
+    pub continuous_supported: bool,
+
+    /// Whether the client said it supports fences, and how many we sent that it hasn't answered.
+    pub fence_supported: bool,
+    fences_in_flight: usize,
+
+    /// The answer to a fence from the client, to be sent once the message after it is handled.
+    pub sync_fence: Option<Fence>,
+}
+
+impl UpdateState {
+    pub fn new(output_format: PixelFormat) -> Self {
+        UpdateState {
+            width: 0,
+            height: 0,
+            input_format: None,
+            bytes_per_pixel: 0,
+            pixels: Vec::new(),
+            output_format,
+            copy_rect: false,
+            copies: Vec::new(),
+            damage: Region::new(),
+            passthrough: Vec::new(),
+            pending: None,
+            requested: Region::new(),
+            last_poll: Instant::now(),
+            last_sent: None,
+            deferred: false,
+            link: LinkState::new(),
+            continuous: None,
+            continuous_supported: false,
+            fence_supported: false,
+            fences_in_flight: 0,
+            sync_fence: None,
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Match the framebuffer's size and pixel format. If either changed, the client is owed the
+    /// whole of it again.
+    pub fn resize(&mut self, width: u16, height: u16, input_format: &PixelFormat) {
+        if self.width == width
+            && self.height == height
+            && self.input_format.as_ref() == Some(input_format)
+        {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.bytes_per_pixel = (input_format.bits_per_pixel as usize).div_ceil(8);
+        self.input_format = Some(input_format.clone());
+        self.pixels = vec![0; width as usize * height as usize * self.bytes_per_pixel];
+        self.requested.intersect(&self.bounds());
+        self.invalidate();
+    }
+
+    /// Mark the whole framebuffer as needing to be sent.
+    pub fn invalidate(&mut self) {
+        self.damage = Region::from(self.bounds());
+        self.copies.clear();
+    }
+
+    /// Fold a frame from the backend into the framebuffer, adding what changed to the damage.
+    pub fn apply(&mut self, fbu: FramebufferUpdate) {
+        for r in fbu.into_rectangles() {
+            let rect = r.rect();
+            if r.encoding_type() == EncodingType::CopyRect {
+                let src = CopyRectEncoding::from_bytes(r.data().encode())
+                    .map(|c| Rect::new(c.src_x(), c.src_y(), rect.width, rect.height))
+                    .filter(|src| self.bounds().contains(src) && self.bounds().contains(&rect));
+                if let Some(src) = src {
+                    self.copy(src, rect);
+                    continue;
+                }
+            }
+
+            let expected_len = rect.area() as usize * self.bytes_per_pixel;
+            let decodable = r.encoding_type() == EncodingType::Raw
+                && r.data().encode().len() == expected_len
+                && self.bounds().contains(&rect);
+            if !decodable {
+                self.passthrough.push(r);
+                continue;
+            }
+
+            if self.copy_rect {
+                if let Some((src, dst)) = self.detect_scroll(rect, r.data().encode()) {
+                    self.copy(src, dst);
+                }
+            }
+            let changed = self.put(rect, r.data().encode());
+            self.damage.union(&changed);
+        }
+    }
+
+    /// Move the pixels of `src` to `dst`, a rectangle of the same size. If the client is up to
+    /// date on `src`, it is told to make the same copy; otherwise `dst` is sent in full.
+    fn copy(&mut self, src: Rect, dst: Rect) {
+        let bpp = self.bytes_per_pixel;
+        let stride = self.width as usize * bpp;
+        let row_len = dst.width as usize * bpp;
+        let copy_row = |pixels: &mut Vec<u8>, row: usize| {
+            let from = (src.y as usize + row) * stride + src.x as usize * bpp;
+            let to = (dst.y as usize + row) * stride + dst.x as usize * bpp;
+            pixels.copy_within(from..from + row_len, to);
+        };
+        // Copy rows in the order that never overwrites a row before it has been read.
+        if dst.y <= src.y {
+            (0..dst.height as usize).for_each(|row| copy_row(&mut self.pixels, row));
+        } else {
+            (0..dst.height as usize)
+                .rev()
+                .for_each(|row| copy_row(&mut self.pixels, row));
+        }
+
+        let src_sent = self.damage.intersection(&Region::from(src)).is_empty();
+        if self.copy_rect && src_sent {
+            self.damage.subtract(&dst);
+            self.copies.push((dst, src.x, src.y));
+        } else {
+            self.damage.add(dst);
+        }
+    }
+
+    /// Look for a block of rows in `src`, the new pixels of `rect`, that were scrolled up or down
+    /// from where they are in the framebuffer, returning where the block was and where it is now.
+    fn detect_scroll(&self, rect: Rect, src: &[u8]) -> Option<(Rect, Rect)> {
+        let bpp = self.bytes_per_pixel;
+        let stride = self.width as usize * bpp;
+        let row_len = rect.width as usize * bpp;
+        let height = rect.height as usize;
+        let old_row = |y: usize| {
+            let start = (rect.y as usize + y) * stride + rect.x as usize * bpp;
+            &self.pixels[start..][..row_len]
+        };
+        let new_row = |y: usize| &src[y * row_len..][..row_len];
+        let hash = |row: &[u8]| {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(row);
+            hasher.finish()
+        };
+
+        let changed: Vec<usize> = (0..height).filter(|&y| new_row(y) != old_row(y)).collect();
+        if changed.len() < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        // Find where each changed row came from, ignoring rows that appear more than once, such
+        // as blank ones, since they could have come from anywhere...
+        let mut index: HashMap<u64, Option<usize>> = HashMap::new();
+        for y in 0..height {
+            index
+                .entry(hash(old_row(y)))
+                .and_modify(|e| *e = None)
+                .or_insert(Some(y));
+        }
+        let mut votes: HashMap<isize, usize> = HashMap::new();
+        for &y in &changed {
+            if let Some(Some(from)) = index.get(&hash(new_row(y))) {
+                *votes.entry(*from as isize - y as isize).or_default() += 1;
+            }
+        }
+        // ...and take the distance most of them moved.
+        let (offset, _) = votes
+            .into_iter()
+            .max_by_key(|&(offset, n)| (n, std::cmp::Reverse(offset.unsigned_abs())))?;
+
+        // The longest run of rows that moved by that much is the scrolled block.
+        let moved = |y: usize| {
+            let from = y as isize + offset;
+            from >= 0 && (from as usize) < height && new_row(y) == old_row(from as usize)
+        };
+        let (mut best, mut start) = (0..0, None);
+        for y in 0..=height {
+            match (y < height && moved(y), start) {
+                (true, None) => start = Some(y),
+                (false, Some(s)) => {
+                    if y - s > best.len() {
+                        best = s..y;
+                    }
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if best.len() < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        let dst = Rect::new(
+            rect.x,
+            rect.y + best.start as u16,
+            rect.width,
+            best.len() as u16,
+        );
+        let src_y = (dst.y as isize + offset) as u16;
+        Some((Rect::new(rect.x, src_y, rect.width, dst.height), dst))
+    }
+
+    /// Copy raw pixels for `rect` into the framebuffer, returning what changed.
+    ///
+    /// The rectangle is compared a tile at a time, so changes far apart are reported separately
+    /// rather than as one rectangle spanning both.
+    fn put(&mut self, rect: Rect, src: &[u8]) -> Region {
+        let changed: Vec<_> = tiles::tiles(rect, TILE_SIZE)
+            .filter_map(|tile| Some((self.put_tile(rect, tile, src)?, tile)))
+            .collect();
+        tiles::merge(changed)
+    }
+
+    /// Copy the part of `src`, the pixels of `rect`, falling in `tile` into the framebuffer,
+    /// returning the bounds of what changed within the tile.
+    fn put_tile(&mut self, rect: Rect, tile: Rect, src: &[u8]) -> Option<Rect> {
+        let bpp = self.bytes_per_pixel;
+        let stride = self.width as usize * bpp;
+        let src_stride = rect.width as usize * bpp;
+        let row_len = tile.width as usize * bpp;
+        let mut changed: Option<Rect> = None;
+
+        for y in tile.y..tile.bottom() as u16 {
+            let src_start = (y - rect.y) as usize * src_stride + (tile.x - rect.x) as usize * bpp;
+            let src_row = &src[src_start..][..row_len];
+            let start = y as usize * stride + tile.x as usize * bpp;
+            let dst_row = &mut self.pixels[start..][..row_len];
+            if src_row == dst_row {
+                continue;
+            }
+
+            let first = src_row
+                .iter()
+                .zip(dst_row.iter())
+                .position(|(a, b)| a != b)
+                .unwrap();
+            let last = src_row
+                .iter()
+                .zip(dst_row.iter())
+                .rposition(|(a, b)| a != b)
+                .unwrap();
+            let x = tile.x + (first / bpp) as u16;
+            let width = (last / bpp - first / bpp + 1) as u16;
+            let row_changed = Rect::new(x, y, width, 1);
+            changed = Some(match changed {
+                Some(c) => c.union(&row_changed),
+                None => row_changed,
+            });
+            dst_row.copy_from_slice(src_row);
+        }
+        changed
+    }
+
+    /// Record a request for an update of `rect`. A non-incremental request is owed all of `rect`,
+    /// whether or not it changed.
+    pub fn request(&mut self, incremental: bool, rect: Rect, at: Instant) {
+        if let Some(rect) = rect.intersection(&self.bounds()) {
+            self.requested.add(rect);
+            if !incremental {
+                self.damage.add(rect);
+            }
+        }
+        self.pending.get_or_insert(at);
+    }
+
+    /// The smallest rectangle covering everything the client asked for, or the whole framebuffer
+    /// if it hasn't asked for anything in particular.
+    pub fn requested_area(&self) -> Rect {
+        let bounds = self.bounds();
+        if self.requested.is_empty() {
+            return bounds;
+        }
+        self.requested
+            .bounds()
+            .intersection(&bounds)
+            .unwrap_or_default()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn last_sent(&self) -> Option<Instant> {
+        self.last_sent
+    }
+
+    pub fn continuous(&self) -> Option<Rect> {
+        self.continuous
+    }
+
+    /// Start keeping the client up to date on `area` without it asking, or stop with `None`.
+    pub fn set_continuous(&mut self, area: Option<Rect>, at: Instant) {
+        self.continuous = area;
+        match area {
+            Some(area) => self.request(true, area, at),
+            None => {
+                self.pending = None;
+                self.requested.clear();
+            }
+        }
+    }
+
+    pub fn fence_sent(&mut self) {
+        self.fences_in_flight += 1;
+    }
+
+    pub fn fence_answered(&mut self) {
+        self.fences_in_flight = self.fences_in_flight.saturating_sub(1);
+    }
+
+    /// Whether continuous updates are held back until the client answers a fence, so that no
+    /// more than a few updates are ever on their way to it.
+    pub fn awaiting_fence(&self) -> bool {
+        self.continuous.is_some() && self.fences_in_flight >= MAX_FENCES_IN_FLIGHT
+    }
+
+    /// If the client is waiting for an update and has something to be sent in the region it
+    /// asked for, build the update, returning it with when it was requested. Damage outside of
+    /// that region, including copies landing outside of it, is kept for a later request. With
+    /// `force`, an update of the whole framebuffer is built even if the client hasn't asked for
+    /// one.
+    pub fn take_update(&mut self, force: bool) -> Option<(FramebufferUpdate, Instant)> {
+        if !(self.is_pending() || force) {
+            return None;
+        }
+        let mut area = Region::from(self.bounds());
+        if !force {
+            area = self.requested.intersection(&area);
+        }
+
+        // A copy is only sent with an update covering where it lands. Any other is sent later as
+        // pixels instead, as is any copy after it made from where it landed, since the client
+        // won't have those pixels to copy from.
+        let mut copies = Vec::new();
+        let mut withheld = Region::new();
+        for (dst, x, y) in std::mem::take(&mut self.copies) {
+            let src = Region::from(Rect::new(x, y, dst.width, dst.height));
+            let requested = Region::from(dst).intersection(&area).area() == dst.area();
+            if requested && withheld.intersection(&src).is_empty() {
+                copies.push((dst, x, y));
+            } else {
+                withheld.add(dst);
+                self.damage.add(dst);
+            }
+        }
+
+        let damage = self.damage.intersection(&area);
+        let (passthrough, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.passthrough)
+            .into_iter()
+            .partition(|r| {
+                area.rects()
+                    .iter()
+                    .any(|a| a.intersection(&r.rect()).is_some())
+            });
+        self.passthrough = rest;
+        if damage.is_empty() && passthrough.is_empty() && copies.is_empty() {
+            return None;
+        }
+        let requested_at = self.pending.take().unwrap_or_else(Instant::now);
+        self.requested.clear();
+        self.last_sent = Some(Instant::now());
+        // With continuous updates, the client is owed the next one as soon as this is sent.
+        if let Some(area) = self.continuous {
+            self.request(true, area, Instant::now());
+        }
+
+        // Copies come first, since they are made from what the client has before this update.
+        let mut rects: Vec<_> = copies
+            .into_iter()
+            .map(|(dst, x, y)| {
+                let copy = CopyRectEncoding::new(x, y);
+                Rectangle::new(dst.x, dst.y, dst.width, dst.height, Box::new(copy))
+            })
+            .collect();
+        rects.extend(passthrough);
+
+        for rect in damage.rects() {
+            self.damage.subtract(rect);
+            let pixels = self.get(rect);
+            rects.push(Rectangle::new(
+                rect.x,
+                rect.y,
+                rect.width,
+                rect.height,
+                Box::new(RawEncoding::new(pixels)),
+            ));
+        }
+        Some((FramebufferUpdate::new(rects), requested_at))
+    }
+
+    /// Copy the raw pixels of `rect` out of the framebuffer.
+    fn get(&self, rect: &Rect) -> Vec<u8> {
+        let bpp = self.bytes_per_pixel;
+        let stride = self.width as usize * bpp;
+        let row_len = rect.width as usize * bpp;
+        let mut out = Vec::with_capacity(rect.height as usize * row_len);
+        for row in rect.y as usize..rect.bottom() as usize {
+            let start = row * stride + rect.x as usize * bpp;
+            out.extend_from_slice(&self.pixels[start..][..row_len]);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::UpdateState;
+    use crate::encodings::{CopyRectEncoding, EncodingType, RawEncoding};
+    use crate::pixel_formats::fourcc;
+    use crate::region::Rect;
+    use crate::rfb::{FramebufferUpdate, Rectangle};
+
+    fn frame(pixels: Vec<u8>) -> FramebufferUpdate {
+        let r = Rectangle::new(0, 0, 4, 4, Box::new(RawEncoding::new(pixels)));
+        FramebufferUpdate::new(vec![r])
+    }
+
+    fn update_rects(state: &mut UpdateState) -> Option<Vec<Rect>> {
+        let (fbu, _) = state.take_update(false)?;
+        Some(fbu.rectangles().iter().map(|r| r.rect()).collect())
+    }
+
+    #[test]
+    fn test_incremental() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.resize(4, 4, &pf);
+        state.apply(frame(vec![0; 64]));
+
+        // The first request is owed everything, as the client has nothing yet.
+        let all = Rect::new(0, 0, 4, 4);
+        state.request(true, all, Instant::now());
+        assert_eq!(update_rects(&mut state), Some(vec![all]));
+
+        // With nothing changed, an incremental request waits.
+        state.apply(frame(vec![0; 64]));
+        state.request(true, all, Instant::now());
+        assert_eq!(update_rects(&mut state), None);
+        assert!(state.is_pending());
+
+        // Once a pixel changes, only that pixel is sent.
+        let mut pixels = vec![0; 64];
+        pixels[(2 * 4 + 1) * 4] = 0xff;
+        state.apply(frame(pixels));
+        assert_eq!(update_rects(&mut state), Some(vec![Rect::new(1, 2, 1, 1)]));
+        assert!(!state.is_pending());
+
+        // A non-incremental request gets the whole framebuffer again.
+        state.request(false, all, Instant::now());
+        assert_eq!(update_rects(&mut state), Some(vec![all]));
+    }
+
+    #[test]
+    fn test_separate_changes() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.resize(192, 64, &pf);
+        let all = Rect::new(0, 0, 192, 64);
+        let frame = |pixels| {
+            let r = Rectangle::new(0, 0, 192, 64, Box::new(RawEncoding::new(pixels)));
+            FramebufferUpdate::new(vec![r])
+        };
+        state.apply(frame(vec![0; 192 * 64 * 4]));
+        state.request(true, all, Instant::now());
+        update_rects(&mut state);
+
+        // Changes in tiles apart from each other are sent as two small rectangles, not one
+        // spanning both.
+        let mut pixels = vec![0; 192 * 64 * 4];
+        pixels[(10 * 192 + 1) * 4] = 0xff;
+        pixels[(20 * 192 + 150) * 4] = 0xff;
+        state.apply(frame(pixels));
+        state.request(true, all, Instant::now());
+        assert_eq!(
+            update_rects(&mut state),
+            Some(vec![Rect::new(1, 10, 1, 1), Rect::new(150, 20, 1, 1)])
+        );
+    }
+
+    /// A 32x32 frame whose rows are each a single, distinct colour.
+    fn rows(colours: impl Iterator<Item = u8>) -> FramebufferUpdate {
+        let pixels = colours.flat_map(|c| vec![c; 32 * 4]).collect();
+        let r = Rectangle::new(0, 0, 32, 32, Box::new(RawEncoding::new(pixels)));
+        FramebufferUpdate::new(vec![r])
+    }
+
+    fn update_encodings(state: &mut UpdateState) -> Vec<(Rect, EncodingType)> {
+        let (fbu, _) = state.take_update(false).unwrap();
+        fbu.rectangles()
+            .iter()
+            .map(|r| (r.rect(), r.encoding_type()))
+            .collect()
+    }
+
+    #[test]
+    fn test_scroll() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.copy_rect = true;
+        state.resize(32, 32, &pf);
+        let all = Rect::new(0, 0, 32, 32);
+        state.apply(rows(0..32));
+        state.request(true, all, Instant::now());
+        update_rects(&mut state);
+
+        // Scrolling up by four rows is sent as a copy of the rows that remain, followed by the
+        // four new ones.
+        state.apply(rows(4..36));
+        state.request(true, all, Instant::now());
+        assert_eq!(
+            update_encodings(&mut state),
+            vec![
+                (Rect::new(0, 0, 32, 28), EncodingType::CopyRect),
+                (Rect::new(0, 28, 32, 4), EncodingType::Raw),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copy_hint() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.copy_rect = true;
+        state.resize(32, 32, &pf);
+        let all = Rect::new(0, 0, 32, 32);
+        state.apply(rows(0..32));
+        state.request(true, all, Instant::now());
+        update_rects(&mut state);
+
+        let hint = || {
+            let copy = Box::new(CopyRectEncoding::new(0, 0));
+            FramebufferUpdate::new(vec![Rectangle::new(0, 16, 32, 8, copy)])
+        };
+        state.apply(hint());
+        state.request(true, all, Instant::now());
+        assert_eq!(
+            update_encodings(&mut state),
+            vec![(Rect::new(0, 16, 32, 8), EncodingType::CopyRect)]
+        );
+        // The copy was made to the framebuffer too, so the same frame shows no further change.
+        state.apply(rows((0..16).chain(0..8).chain(24..32)));
+        state.request(true, all, Instant::now());
+        assert_eq!(state.take_update(false).map(|_| ()), None);
+
+        // A client without CopyRect gets the pixels instead.
+        state.copy_rect = false;
+        state.apply(hint());
+        assert_eq!(
+            update_encodings(&mut state),
+            vec![(Rect::new(0, 16, 32, 8), EncodingType::Raw)]
+        );
+    }
+
+    #[test]
+    fn test_copy_outside_request() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.copy_rect = true;
+        state.resize(32, 32, &pf);
+        state.apply(rows(0..32));
+        state.request(true, Rect::new(0, 0, 32, 32), Instant::now());
+        update_rects(&mut state);
+
+        // A copy to a part of the framebuffer the client didn't ask about, then one from there
+        // to the part it did.
+        let copy = |x, y, dst: Rect| {
+            let copy = Box::new(CopyRectEncoding::new(x, y));
+            Rectangle::new(dst.x, dst.y, dst.width, dst.height, copy)
+        };
+        let top = Rect::new(0, 0, 32, 8);
+        let middle = Rect::new(0, 16, 32, 8);
+        state.apply(FramebufferUpdate::new(vec![
+            copy(0, 8, middle),
+            copy(0, 16, top),
+        ]));
+
+        // The second copy can't be made by a client that wasn't sent the first, so the part asked
+        // for is sent as pixels...
+        state.request(true, top, Instant::now());
+        assert_eq!(update_encodings(&mut state), vec![(top, EncodingType::Raw)]);
+
+        // ...and the first copy's destination is kept until the client asks for it.
+        state.request(true, Rect::new(0, 0, 32, 32), Instant::now());
+        assert_eq!(
+            update_encodings(&mut state),
+            vec![(middle, EncodingType::Raw)]
+        );
+    }
+
+    #[test]
+    fn test_requested_region() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.resize(4, 4, &pf);
+        state.apply(frame(vec![0; 64]));
+
+        // Only the requested part of the damage is sent, clipped to the framebuffer.
+        state.request(true, Rect::new(2, 0, 10, 2), Instant::now());
+        assert_eq!(state.requested_area(), Rect::new(2, 0, 2, 2));
+        assert_eq!(update_rects(&mut state), Some(vec![Rect::new(2, 0, 2, 2)]));
+
+        // The rest is kept until the client asks for it.
+        state.request(true, Rect::new(0, 0, 4, 4), Instant::now());
+        let sent = update_rects(&mut state).unwrap();
+        assert_eq!(sent.iter().map(Rect::area).sum::<u64>(), 16 - 4);
+        assert!(sent
+            .iter()
+            .all(|r| r.intersection(&Rect::new(2, 0, 2, 2)).is_none()));
+    }
+
+    #[test]
+    fn test_request_clipped_to_framebuffer() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut state = UpdateState::new(pf.clone());
+        state.resize(4, 4, &pf);
+        state.apply(frame(vec![0; 64]));
+        update_rects(&mut state);
+
+        // A request reaching past the last coordinate only asks for what's on screen.
+        let far = Rect::new(2, 2, u16::MAX, u16::MAX);
+        state.request(false, far, Instant::now());
+        assert_eq!(state.requested_area(), Rect::new(2, 2, 2, 2));
+        assert_eq!(update_rects(&mut state), Some(vec![Rect::new(2, 2, 2, 2)]));
+
+        // Shrinking the framebuffer shrinks what's been asked for with it.
+        state.request(true, Rect::new(0, 0, 4, 4), Instant::now());
+        state.resize(2, 2, &pf);
+        assert_eq!(state.requested_area(), Rect::new(0, 0, 2, 2));
+    }
+}
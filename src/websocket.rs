@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! RFB over WebSockets
+//!
+//! Browser clients such as noVNC tunnel the RFB protocol over a WebSocket, one binary message per
+//! chunk of the byte stream. This module accepts such connections and turns them back into a
+//! plain byte stream that a [`VncServer`](crate::server::VncServer) can serve.
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::select;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+
+use crate::rfb::RfbStream;
+
+/// Size of the buffers between the WebSocket and the byte stream handed to the server.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// The subprotocol noVNC asks for to get binary rather than base64 encoded frames.
+const BINARY_PROTOCOL: &str = "binary";
+
+/// Complete the WebSocket handshake on `stream`.
+///
+/// `route` is given the request path and returns what the connection should be handed to, or
+/// `None` to reject the connection with a 404.
+pub(crate) async fn accept<S, T>(
+    stream: S,
+    route: impl FnOnce(&str) -> Option<T> + Unpin,
+) -> Result<(WebSocketStream<S>, T), WsError>
+where
+    S: RfbStream,
+{
+    let mut target = None;
+    // The callback's signature is dictated by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, mut resp: Response| -> Result<Response, ErrorResponse> {
+        let path = req.uri().path();
+        target = route(path);
+        if target.is_none() {
+            debug!("no WebSocket route for {:?}", path);
+            let mut err = ErrorResponse::new(Some(format!("no display at {}", path)));
+            *err.status_mut() = StatusCode::NOT_FOUND;
+            return Err(err);
+        }
+
+        let offers_binary = req
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|p| p.trim() == BINARY_PROTOCOL);
+        if offers_binary {
+            resp.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(BINARY_PROTOCOL),
+            );
+        }
+        Ok(resp)
+    };
+
+    let ws = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+    // The handshake only succeeds if the callback found a route.
+    Ok((ws, target.unwrap()))
+}
+
+/// Turn a WebSocket into a byte stream, moving data between the two on a background task until
+/// either side closes.
+pub(crate) fn bridge<S: RfbStream + 'static>(ws: WebSocketStream<S>) -> DuplexStream {
+    let (ours, theirs) = tokio::io::duplex(BUF_SIZE);
+    tokio::spawn(pump(ws, theirs));
+    ours
+}
+
+async fn pump<S: RfbStream>(mut ws: WebSocketStream<S>, io: DuplexStream) {
+    let (mut rd, mut wr) = tokio::io::split(io);
+    let mut buf = vec![0u8; BUF_SIZE];
+
+    loop {
+        select! {
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    if wr.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                // Pongs are queued by the WebSocket itself and sent on the next flush.
+                Some(Ok(Message::Ping(_))) => {
+                    if ws.flush().await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+
+            n = rd.read(&mut buf) => match n {
+                Ok(0) | Err(_) => {
+                    let _ = ws.close(None).await;
+                    break;
+                }
+                Ok(n) => {
+                    if ws.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+}